@@ -0,0 +1,12 @@
+/// The SF2 concave curve, mapping 0..=1 onto 0..=1 so equal steps are roughly equal steps in loudness
+pub fn concave(value: f32) -> f32 {
+    if value >= 1.0 {
+        return 1.0;
+    }
+    (-40.0 / 96.0 * (1.0 - value).log10()).clamp(0.0, 1.0)
+}
+
+/// The SF2 convex curve, the concave curve flipped both ways
+pub fn convex(value: f32) -> f32 {
+    1.0 - concave(1.0 - value)
+}
@@ -0,0 +1,307 @@
+use crate::soundfont::SoundFontRegion;
+
+/// Attenuation in dB past which a voice is considered silent
+const SILENCE: f32 = 96.0;
+
+/// Seconds a stolen voice takes to fade from full level to silence
+const STEAL_RELEASE: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeStage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// The SF2 volume envelope: delay, attack, hold, decay, sustain and release.
+/// Attack ramps linearly in amplitude, decay and release ramp linearly in dB.
+pub struct VolumeEnvelope {
+    /// In seconds
+    delay: f32,
+    /// In seconds
+    attack: f32,
+    /// In seconds
+    hold: f32,
+    /// Seconds it would take to decay all the way to silence
+    decay: f32,
+    /// Attenuation in dB
+    sustain: f32,
+    /// Seconds it would take to release all the way to silence
+    release: f32,
+    stage: EnvelopeStage,
+    stage_time: f32,
+    /// Attenuation in dB, only used from the decay stage onwards
+    attenuation: f32,
+    /// Attenuation in dB at the moment the release started
+    release_attenuation: f32,
+    gain: f32,
+}
+
+impl VolumeEnvelope {
+    pub fn new(region: &SoundFontRegion, note: i32) -> Self {
+        let mut envelope = Self {
+            delay: region.delay_volume_envelope(),
+            attack: region.attack_volume_envelope(),
+            hold: region.hold_volume_envelope()
+                * key_scaling(region.key_number_to_volume_envelope_hold(), note),
+            decay: region.decay_volume_envelope()
+                * key_scaling(region.key_number_to_volume_envelope_decay(), note),
+            sustain: region.sustain_volume_envelope().clamp(0.0, SILENCE),
+            release: region.release_volume_envelope(),
+            stage: EnvelopeStage::Delay,
+            stage_time: 0.0,
+            attenuation: 0.0,
+            release_attenuation: 0.0,
+            gain: 0.0,
+        };
+        envelope.update_stage();
+        envelope
+    }
+
+    /// The current linear gain of the envelope
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Whether the envelope has gone silent for good, by releasing all the way or by decaying to a silent sustain level.
+    /// The SF2 spec lets players end notes on a silent sustain, so looped samples don't hold silent voices until note off.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.stage,
+            EnvelopeStage::Decay | EnvelopeStage::Sustain | EnvelopeStage::Release
+        ) && self.attenuation >= SILENCE
+    }
+
+    pub fn release(&mut self) {
+        if self.stage == EnvelopeStage::Release {
+            return;
+        }
+        self.attenuation = gain_to_decibels(self.gain);
+        self.release_attenuation = self.attenuation;
+        self.stage = EnvelopeStage::Release;
+        self.stage_time = 0.0;
+    }
+
+    /// Releases quickly, whatever the release time is
+    pub fn steal(&mut self) {
+        // Restart the release from the current level so shortening it doesn't make the level jump
+        self.stage = EnvelopeStage::Sustain;
+        self.release = self.release.min(STEAL_RELEASE);
+        self.release();
+    }
+
+    pub fn tick(&mut self, delta: f32) {
+        self.stage_time += delta;
+        self.update_stage();
+    }
+
+    fn update_stage(&mut self) {
+        loop {
+            let next_stage = match self.stage {
+                EnvelopeStage::Delay if self.stage_time >= self.delay => {
+                    Some((EnvelopeStage::Attack, self.delay))
+                }
+                EnvelopeStage::Attack if self.stage_time >= self.attack => {
+                    Some((EnvelopeStage::Hold, self.attack))
+                }
+                EnvelopeStage::Hold if self.stage_time >= self.hold => {
+                    Some((EnvelopeStage::Decay, self.hold))
+                }
+                EnvelopeStage::Decay if self.attenuation >= self.sustain => {
+                    Some((EnvelopeStage::Sustain, self.stage_time))
+                }
+                _ => None,
+            };
+            let Some((stage, duration)) = next_stage else {
+                break;
+            };
+            self.stage = stage;
+            self.stage_time -= duration;
+        }
+
+        match self.stage {
+            EnvelopeStage::Delay => self.gain = 0.0,
+            EnvelopeStage::Attack => self.gain = self.stage_time / self.attack,
+            EnvelopeStage::Hold => self.gain = 1.0,
+            EnvelopeStage::Decay => {
+                self.attenuation = (SILENCE * self.stage_time / self.decay).min(self.sustain);
+                self.gain = decibels_to_gain(self.attenuation);
+            }
+            EnvelopeStage::Sustain => {
+                self.attenuation = self.sustain;
+                self.gain = decibels_to_gain(self.attenuation);
+            }
+            EnvelopeStage::Release => {
+                self.attenuation = (self.release_attenuation
+                    + SILENCE * self.stage_time / self.release)
+                    .min(SILENCE);
+                self.gain = if self.attenuation >= SILENCE {
+                    0.0
+                } else {
+                    decibels_to_gain(self.attenuation)
+                };
+            }
+        }
+    }
+}
+
+/// Multiplier applied to an envelope time by the keynum-to-hold/decay generators, relative to middle C
+fn key_scaling(timecents_per_key: i32, note: i32) -> f32 {
+    2_f32.powf((timecents_per_key * (60 - note)) as f32 / 1200.0)
+}
+
+pub fn decibels_to_gain(attenuation: f32) -> f32 {
+    10_f32.powf(-attenuation / 20.0)
+}
+
+fn gain_to_decibels(gain: f32) -> f32 {
+    if gain <= 0.0 {
+        SILENCE
+    } else {
+        (-20.0 * gain.log10()).min(SILENCE)
+    }
+}
+
+/// The SF2 modulation envelope: delay, attack, hold, decay, sustain and release.
+/// Every stage ramps linearly between 0 and 1.
+pub struct ModulationEnvelope {
+    /// In seconds
+    delay: f32,
+    /// In seconds
+    attack: f32,
+    /// In seconds
+    hold: f32,
+    /// Seconds it would take to decay all the way to 0
+    decay: f32,
+    /// Between 0 and 1
+    sustain: f32,
+    /// Seconds it would take to release all the way to 0 from 1
+    release: f32,
+    stage: EnvelopeStage,
+    stage_time: f32,
+    /// Value at the moment the release started
+    release_value: f32,
+    value: f32,
+}
+
+impl ModulationEnvelope {
+    pub fn new(region: &SoundFontRegion, note: i32) -> Self {
+        let mut envelope = Self {
+            delay: region.delay_modulation_envelope(),
+            attack: region.attack_modulation_envelope(),
+            hold: region.hold_modulation_envelope()
+                * key_scaling(region.key_number_to_modulation_envelope_hold(), note),
+            decay: region.decay_modulation_envelope()
+                * key_scaling(region.key_number_to_modulation_envelope_decay(), note),
+            sustain: 1.0 - region.sustain_modulation_envelope().clamp(0.0, 100.0) / 100.0,
+            release: region.release_modulation_envelope(),
+            stage: EnvelopeStage::Delay,
+            stage_time: 0.0,
+            release_value: 0.0,
+            value: 0.0,
+        };
+        envelope.update_stage();
+        envelope
+    }
+
+    /// Between 0 and 1
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    pub fn release(&mut self) {
+        if self.stage == EnvelopeStage::Release {
+            return;
+        }
+        self.release_value = self.value;
+        self.stage = EnvelopeStage::Release;
+        self.stage_time = 0.0;
+    }
+
+    pub fn tick(&mut self, delta: f32) {
+        self.stage_time += delta;
+        self.update_stage();
+    }
+
+    fn update_stage(&mut self) {
+        loop {
+            let next_stage = match self.stage {
+                EnvelopeStage::Delay if self.stage_time >= self.delay => {
+                    Some((EnvelopeStage::Attack, self.delay))
+                }
+                EnvelopeStage::Attack if self.stage_time >= self.attack => {
+                    Some((EnvelopeStage::Hold, self.attack))
+                }
+                EnvelopeStage::Hold if self.stage_time >= self.hold => {
+                    Some((EnvelopeStage::Decay, self.hold))
+                }
+                EnvelopeStage::Decay if self.value <= self.sustain => {
+                    Some((EnvelopeStage::Sustain, self.stage_time))
+                }
+                _ => None,
+            };
+            let Some((stage, duration)) = next_stage else {
+                break;
+            };
+            self.stage = stage;
+            self.stage_time -= duration;
+        }
+
+        self.value = match self.stage {
+            EnvelopeStage::Delay => 0.0,
+            EnvelopeStage::Attack => self.stage_time / self.attack,
+            EnvelopeStage::Hold => 1.0,
+            EnvelopeStage::Decay => (1.0 - self.stage_time / self.decay).max(self.sustain),
+            EnvelopeStage::Sustain => self.sustain,
+            EnvelopeStage::Release => {
+                (self.release_value - self.stage_time / self.release).max(0.0)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An envelope that decays over a second straight from the start
+    fn decaying_envelope(sustain: f32) -> VolumeEnvelope {
+        let mut envelope = VolumeEnvelope {
+            delay: 0.0,
+            attack: 0.0,
+            hold: 0.0,
+            decay: 1.0,
+            sustain,
+            release: 1.0,
+            stage: EnvelopeStage::Delay,
+            stage_time: 0.0,
+            attenuation: 0.0,
+            release_attenuation: 0.0,
+            gain: 0.0,
+        };
+        envelope.update_stage();
+        envelope
+    }
+
+    #[test]
+    fn silent_sustain_finishes_after_decay() {
+        let mut envelope = decaying_envelope(SILENCE);
+        envelope.tick(0.5);
+        assert!(!envelope.is_finished());
+        envelope.tick(0.5);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn audible_sustain_holds_until_released() {
+        let mut envelope = decaying_envelope(40.0);
+        envelope.tick(2.0);
+        assert!(!envelope.is_finished());
+        envelope.release();
+        envelope.tick(1.0);
+        assert!(envelope.is_finished());
+    }
+}
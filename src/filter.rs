@@ -0,0 +1,88 @@
+use std::f32::consts::{PI, SQRT_2};
+
+/// A resonant biquad low-pass filter, configured from the SF2 `initialFilterFc` and `initialFilterQ` generators
+pub struct LowPassFilter {
+    samples_per_second: f32,
+    /// In Hz
+    cutoff: f32,
+    /// Resonance peak height in dB
+    resonance: f32,
+    /// Above roughly the Nyquist frequency the filter would do nothing, so it's skipped
+    active: bool,
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl LowPassFilter {
+    pub fn new(samples_per_second: f32, cutoff: f32, resonance: f32) -> Self {
+        let mut filter = Self {
+            samples_per_second,
+            cutoff: f32::NAN,
+            resonance,
+            active: false,
+            b0: 0.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        };
+        filter.set_cutoff(cutoff);
+        filter
+    }
+
+    /// Recalculates the coefficients if the cutoff frequency (in Hz) changed
+    pub fn set_cutoff(&mut self, cutoff: f32) {
+        if cutoff == self.cutoff {
+            return;
+        }
+        self.cutoff = cutoff;
+
+        self.active = cutoff < 0.499 * self.samples_per_second;
+        if !self.active {
+            return;
+        }
+
+        // The Q that gives a resonance peak of the requested height, to within a few percent
+        let resonance = 10_f32.powf(self.resonance / 20.0);
+        let q = resonance - (1.0 - 1.0 / SQRT_2) / (1.0 + 6.0 * (resonance - 1.0));
+
+        let w = 2.0 * PI * cutoff / self.samples_per_second;
+        let cos_w = w.cos();
+        let alpha = w.sin() / (2.0 * q);
+
+        let a0 = 1.0 + alpha;
+        self.b0 = (1.0 - cos_w) / 2.0 / a0;
+        self.b1 = (1.0 - cos_w) / a0;
+        self.b2 = (1.0 - cos_w) / 2.0 / a0;
+        self.a1 = -2.0 * cos_w / a0;
+        self.a2 = (1.0 - alpha) / a0;
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = if self.active {
+            self.b0 * input + self.b1 * self.x1 + self.b2 * self.x2
+                - self.a1 * self.y1
+                - self.a2 * self.y2
+        } else {
+            input
+        };
+
+        self.x2 = self.x1;
+        self.x1 = input;
+        self.y2 = self.y1;
+        self.y1 = output;
+
+        output
+    }
+}
@@ -0,0 +1,112 @@
+use std::f32::consts::PI;
+
+/// Taps on either side of the read position used by [`InterpolationMode::Sinc`]
+const SINC_RADIUS: i64 = 8;
+
+/// How voices read between the samples of a SoundFont wave, trading quality against CPU
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InterpolationMode {
+    /// Picks the closest sample, cheapest and crunchiest
+    Nearest,
+    /// Blends the two surrounding samples
+    #[default]
+    Linear,
+    /// Cubic Hermite spline through the four surrounding samples
+    Cubic,
+    /// Windowed sinc that also band-limits pitched up samples, the most expensive
+    Sinc,
+}
+
+impl InterpolationMode {
+    /// How many samples away from the read position get read at most
+    pub fn radius(self) -> i64 {
+        match self {
+            InterpolationMode::Nearest | InterpolationMode::Linear => 1,
+            InterpolationMode::Cubic => 2,
+            InterpolationMode::Sinc => SINC_RADIUS,
+        }
+    }
+
+    /// Reads the wave at `position`, where `wave` returns the sample at an integer index.
+    /// `speed` is how many samples are skipped per output sample.
+    pub fn interpolate(self, position: f64, speed: f32, wave: impl Fn(i64) -> f32) -> f32 {
+        let index = position.floor() as i64;
+        let fraction = position.fract() as f32;
+        match self {
+            InterpolationMode::Nearest => wave(position.round() as i64),
+            InterpolationMode::Linear => {
+                let current = wave(index);
+                let next = wave(index + 1);
+                current + (next - current) * fraction
+            }
+            InterpolationMode::Cubic => {
+                let previous = wave(index - 1);
+                let current = wave(index);
+                let next = wave(index + 1);
+                let after_next = wave(index + 2);
+                let c1 = (next - previous) / 2.0;
+                let c2 = previous - 2.5 * current + 2.0 * next - after_next / 2.0;
+                let c3 = (after_next - previous) / 2.0 + 1.5 * (current - next);
+                ((c3 * fraction + c2) * fraction + c1) * fraction + current
+            }
+            InterpolationMode::Sinc => {
+                // Lower the cutoff when skipping samples so pitched up waves don't alias
+                let cutoff = 1.0 / speed.max(1.0);
+                let first_x = (1 - SINC_RADIUS) as f32 - fraction;
+
+                // The taps are evenly spaced, so the sines and cosines are stepped by rotation instead of recalculated
+                let mut sinc_phase = Rotation::new(PI * cutoff * first_x, PI * cutoff);
+                let mut window_phase = Rotation::new(
+                    PI * (first_x / SINC_RADIUS as f32 + 1.0),
+                    PI / SINC_RADIUS as f32,
+                );
+
+                let mut sum = 0.0;
+                for offset in 1 - SINC_RADIUS..=SINC_RADIUS {
+                    let x = offset as f32 - fraction;
+                    let sinc = if x.abs() < 1e-6 {
+                        cutoff
+                    } else {
+                        sinc_phase.sin / (PI * x)
+                    };
+                    // Blackman window
+                    let cos = window_phase.cos;
+                    let window = 0.42 - 0.5 * cos + 0.08 * (2.0 * cos * cos - 1.0);
+                    sum += wave(index + offset) * sinc * window;
+
+                    sinc_phase.step();
+                    window_phase.step();
+                }
+                sum
+            }
+        }
+    }
+}
+
+/// A sine and cosine pair that can be advanced by a fixed angle
+struct Rotation {
+    sin: f32,
+    cos: f32,
+    step_sin: f32,
+    step_cos: f32,
+}
+
+impl Rotation {
+    fn new(angle: f32, step: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let (step_sin, step_cos) = step.sin_cos();
+        Self {
+            sin,
+            cos,
+            step_sin,
+            step_cos,
+        }
+    }
+
+    fn step(&mut self) {
+        (self.sin, self.cos) = (
+            self.sin * self.step_cos + self.cos * self.step_sin,
+            self.cos * self.step_cos - self.sin * self.step_sin,
+        );
+    }
+}
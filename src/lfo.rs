@@ -0,0 +1,42 @@
+/// A triangle LFO as used by the SF2 vibrato and modulation LFOs.
+/// Stays at 0 during the delay, then starts rising towards 1.
+pub struct Lfo {
+    /// In seconds
+    delay: f32,
+    /// In Hz
+    frequency: f32,
+    time: f32,
+    value: f32,
+}
+
+impl Lfo {
+    pub fn new(delay: f32, frequency: f32) -> Self {
+        Self {
+            delay,
+            frequency,
+            time: 0.0,
+            value: 0.0,
+        }
+    }
+
+    /// Between -1 and 1
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    pub fn tick(&mut self, delta: f32) {
+        self.time += delta;
+        if self.time < self.delay {
+            return;
+        }
+
+        let phase = ((self.time - self.delay) * self.frequency).fract();
+        self.value = if phase < 0.25 {
+            4.0 * phase
+        } else if phase < 0.75 {
+            2.0 - 4.0 * phase
+        } else {
+            4.0 * phase - 4.0
+        };
+    }
+}
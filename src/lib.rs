@@ -1,29 +1,46 @@
 use bevy::audio::AddAudioSource;
 use bevy::prelude::*;
 
+pub use interpolation::InterpolationMode;
 pub use midi::MidiTrack;
+pub use modulator::{
+    Modulator, ModulatorCurve, ModulatorDestination, ModulatorInput, ModulatorSource,
+};
 pub use notes::Note;
+pub use polyphony::{RetriggerPolicy, VoiceStealingPolicy};
 pub use rustysynth::SoundFont;
 pub use source::{
     MidiAudio, MidiAudioTrack, MidiAudioTrackHandle, MidiBufferMessage, MidiQueueEvent,
-    MidiQueueEventType, MidiQueueLooping, MidiQueueTiming, SyncedMidiInfo,
+    MidiQueueEventType, MidiQueueLooping, MidiQueueTiming, MidiSample, OutputChannels,
+    SyncedMidiInfo,
 };
+pub use tuning::{ScalaError, TuningTable};
 
+mod curve;
+mod envelope;
+mod filter;
+mod interpolation;
+mod lfo;
 mod midi;
+mod modulator;
 mod notes;
+mod polyphony;
+mod soundfont;
 mod source;
+mod tuning;
 
 #[derive(Default)]
 pub struct SoundyPlugin;
 
 impl Plugin for SoundyPlugin {
     fn build(&self, app: &mut App) {
-        app.add_audio_source::<MidiAudio>()
-            .add_systems(PreUpdate, tick_sequencers);
+        app.add_audio_source::<MidiAudio<i16>>()
+            .add_audio_source::<MidiAudio<f32>>()
+            .add_systems(PreUpdate, (tick_sequencers::<i16>, tick_sequencers::<f32>));
     }
 }
 
-fn tick_sequencers(mut audios: ResMut<Assets<MidiAudio>>, time: Res<Time>) {
+fn tick_sequencers<S: MidiSample>(mut audios: ResMut<Assets<MidiAudio<S>>>, time: Res<Time>) {
     for (_id, audio) in audios.iter_mut() {
         audio.tick(time.delta());
     }
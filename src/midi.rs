@@ -6,6 +6,9 @@ use augmented_midi::{
     parse_midi_file,
 };
 use itertools::Itertools;
+use num_enum::TryFromPrimitive;
+
+use crate::tuning::{Retuning, parse_mts_pitch};
 
 #[derive(Debug, Clone)]
 pub struct MidiTrackAccumulateEvent {
@@ -58,6 +61,57 @@ impl MidiTrack {
                                 channel: (*channel).max(i as u8),
                                 note: *note,
                             },
+                            MIDITrackInner::Message(MIDIMessage::ProgramChange {
+                                channel,
+                                program_number,
+                            }) => MidiEvent::ProgramChange {
+                                channel: (*channel).max(i as u8),
+                                program: *program_number,
+                            },
+                            MIDITrackInner::Message(MIDIMessage::ControlChange {
+                                channel,
+                                controller_number: controller_number @ (0 | 32),
+                                value,
+                            }) => MidiEvent::BankSelect {
+                                channel: (*channel).max(i as u8),
+                                bank: *value,
+                                fine: *controller_number == 32,
+                            },
+                            MIDITrackInner::Message(MIDIMessage::ControlChange {
+                                channel,
+                                controller_number,
+                                value,
+                            }) => MidiEvent::ControlChange {
+                                channel: (*channel).max(i as u8),
+                                controller: *controller_number,
+                                value: *value,
+                            },
+                            MIDITrackInner::Message(MIDIMessage::ChannelPressure {
+                                channel,
+                                pressure,
+                            }) => MidiEvent::ChannelPressure {
+                                channel: (*channel).max(i as u8),
+                                pressure: *pressure,
+                            },
+                            MIDITrackInner::Message(MIDIMessage::PolyphonicKeyPressure {
+                                channel,
+                                note,
+                                pressure,
+                            }) => MidiEvent::PolyPressure {
+                                channel: (*channel).max(i as u8),
+                                note: *note,
+                                pressure: *pressure,
+                            },
+                            MIDITrackInner::Message(MIDIMessage::PitchWheelChange {
+                                channel,
+                                value,
+                            }) => MidiEvent::PitchBend {
+                                channel: (*channel).max(i as u8),
+                                value: *value,
+                            },
+                            MIDITrackInner::Message(MIDIMessage::SysExMessage(sys_ex)) => {
+                                parse_sys_ex(strip_sys_ex_length(sys_ex.message.borrow())?)?
+                            }
                             MIDITrackInner::Meta(meta) if meta.meta_type == 0x51 => {
                                 let microseconds_per_beat = u32::from_be_bytes([
                                     0,
@@ -101,9 +155,406 @@ impl MidiTrack {
     }
 }
 
+/// Skips the variable length quantity MIDI files store a system exclusive message's length in,
+/// which the parser leaves at the start of every system exclusive event
+fn strip_sys_ex_length(message: &[u8]) -> Option<&[u8]> {
+    let length_bytes = message.iter().position(|&byte| byte & 0x80 == 0)? + 1;
+    message.get(length_bytes..)
+}
+
+/// Recognizes the system exclusive messages that channels respond to, without the length MIDI files store before them
+fn parse_sys_ex(message: &[u8]) -> Option<MidiEvent> {
+    let message = message.strip_prefix(&[0xf0]).unwrap_or(message);
+    let message = message.strip_suffix(&[0xf7]).unwrap_or(message);
+    match message {
+        // GM System On and GM2 System On
+        [0x7e, _, 0x09, 0x01 | 0x03] => Some(MidiEvent::SystemReset),
+        // GS Reset
+        [0x41, _, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, _] => Some(MidiEvent::SystemReset),
+        // XG System On
+        [0x43, device, 0x4c, 0x00, 0x00, 0x7e, 0x00] if device & 0xf0 == 0x10 => {
+            Some(MidiEvent::SystemReset)
+        }
+        // GS "use for rhythm part", where part 10 comes first
+        [
+            0x41,
+            _,
+            0x42,
+            0x12,
+            0x40,
+            part @ 0x10..=0x1f,
+            0x15,
+            drum_map,
+            _,
+        ] => {
+            let part = part & 0x0f;
+            Some(MidiEvent::DrumPart {
+                channel: match part {
+                    0 => 9,
+                    1..=9 => part - 1,
+                    _ => part,
+                },
+                drums: *drum_map != 0,
+            })
+        }
+        // MTS bulk tuning dump, without and with a bank, with a 16 character name before the pitches
+        [0x7e, _, 0x08, 0x01, program, rest @ ..]
+        | [0x7e, _, 0x08, 0x04, _, program, rest @ ..]
+            if rest.len() >= 16 + 128 * 3 =>
+        {
+            let bank = match message[3] {
+                0x04 => message[4],
+                _ => 0,
+            };
+            let keys = rest[16..16 + 128 * 3]
+                .chunks_exact(3)
+                .enumerate()
+                .filter_map(|(key, pitch)| {
+                    Some((key as u8, parse_mts_pitch(pitch[0], pitch[1], pitch[2])?))
+                })
+                .collect();
+            Some(MidiEvent::RetuneProgram {
+                bank,
+                program: *program,
+                retuning: Retuning::Keys(keys),
+            })
+        }
+        // MTS single note tuning change, without and with a bank
+        [0x7f, _, 0x08, 0x02, program, count, changes @ ..]
+        | [0x7e | 0x7f, _, 0x08, 0x07, _, program, count, changes @ ..] => {
+            let bank = match message[3] {
+                0x07 => message[4],
+                _ => 0,
+            };
+            let keys = changes
+                .chunks_exact(4)
+                .take(*count as usize)
+                .filter_map(|change| {
+                    Some((change[0], parse_mts_pitch(change[1], change[2], change[3])?))
+                })
+                .collect();
+            Some(MidiEvent::RetuneProgram {
+                bank,
+                program: *program,
+                retuning: Retuning::Keys(keys),
+            })
+        }
+        // MTS scale/octave tuning, in 1 byte form (-64 to 63 cents) and 2 byte form (-100 to 100 cents)
+        [
+            0x7e | 0x7f,
+            _,
+            0x08,
+            form @ (0x08 | 0x09),
+            channels_high,
+            channels_middle,
+            channels_low,
+            offsets @ ..,
+        ] => {
+            let mut octave = [0.0; 12];
+            if *form == 0x08 {
+                let offsets = offsets.get(..12)?;
+                for (note, &offset) in octave.iter_mut().zip(offsets) {
+                    *note = offset as f32 - 64.0;
+                }
+            } else {
+                let offsets = offsets.get(..24)?;
+                for (note, offset) in octave.iter_mut().zip(offsets.chunks_exact(2)) {
+                    let offset = ((offset[0] as u16) << 7) | offset[1] as u16;
+                    *note = (offset as f32 - 8192.0) / 8192.0 * 100.0;
+                }
+            }
+            Some(MidiEvent::Retune {
+                channels: ((*channels_high as u16 & 0x03) << 14)
+                    | ((*channels_middle as u16 & 0x7f) << 7)
+                    | (*channels_low as u16 & 0x7f),
+                retuning: Retuning::Octave(octave),
+            })
+        }
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MidiEvent {
-    NoteOn { channel: u8, note: u8, velocity: u8 },
-    NoteOff { channel: u8, note: u8 },
-    SetTempo { tempo: f64 },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    SetTempo {
+        tempo: f64,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// Takes effect on the next program change. `fine` is CC32 instead of CC0.
+    BankSelect {
+        channel: u8,
+        bank: u8,
+        fine: bool,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// Aftertouch for the whole channel
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// Aftertouch for a single note
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    /// `value` is 14 bits, centered on 8192
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// A GM System On, GS Reset or XG System On, putting every channel back to how it started
+    SystemReset,
+    /// Switches a channel between drum kits and melodic instruments
+    DrumPart {
+        channel: u8,
+        drums: bool,
+    },
+    /// A MIDI Tuning Standard scale/octave tuning, which applies straight away to every channel with a bit set in `channels`
+    Retune {
+        channels: u16,
+        retuning: Retuning,
+    },
+    /// A MIDI Tuning Standard bulk dump or note change, which changes a stored tuning program.
+    /// Channels pick a tuning program with RPN 3 and its bank with RPN 4, starting out on program 0 of bank 0.
+    RetuneProgram {
+        bank: u8,
+        program: u8,
+        retuning: Retuning,
+    },
+}
+
+/// The MIDI control change numbers that channels respond to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Controller {
+    Modulation = 1,
+    PortamentoTime = 5,
+    DataEntry = 6,
+    Volume = 7,
+    Pan = 10,
+    Expression = 11,
+    DataEntryFine = 38,
+    Sustain = 64,
+    Portamento = 65,
+    Sostenuto = 66,
+    Soft = 67,
+    NonRegisteredParameterFine = 98,
+    NonRegisteredParameter = 99,
+    RegisteredParameterFine = 100,
+    RegisteredParameter = 101,
+    AllSoundOff = 120,
+    ResetAllControllers = 121,
+    AllNotesOff = 123,
+    MonoMode = 126,
+    PolyMode = 127,
+}
+
+/// The registered parameter numbers that channels respond to, selected by CC101 and CC100
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u16)]
+pub enum RegisteredParameter {
+    PitchBendRange = 0,
+    FineTuning = 1,
+    CoarseTuning = 2,
+    TuningProgram = 3,
+    TuningBank = 4,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bulk tuning dump for program 5 where key 0 is marked "no change" and every other key is a quarter tone sharp
+    fn bulk_dump() -> Vec<u8> {
+        let mut message = vec![0x7e, 0x7f, 0x08, 0x01, 0x05];
+        message.extend(b"Quarter tone up ");
+        message.extend([0x7f, 0x7f, 0x7f]);
+        for key in 1..128 {
+            message.extend([key, 0x20, 0x00]);
+        }
+        // Checksum
+        message.push(0x00);
+        message
+    }
+
+    fn assert_quarter_tone_dump(event: Option<MidiEvent>) {
+        let Some(MidiEvent::RetuneProgram {
+            bank: 0,
+            program: 5,
+            retuning: Retuning::Keys(keys),
+        }) = event
+        else {
+            panic!("expected a tuning program 5 retune, got {event:?}");
+        };
+        assert_eq!(keys.len(), 127);
+        assert_eq!(keys[0], (1, 125.0));
+        assert_eq!(keys[126], (127, 12725.0));
+    }
+
+    #[test]
+    fn bulk_dump_without_the_length_prefix() {
+        let mut message = vec![0xf0];
+        message.extend(bulk_dump());
+        message.push(0xf7);
+        assert_quarter_tone_dump(parse_sys_ex(&message));
+    }
+
+    /// Parses a message the way it's stored in a MIDI file, after its length, which counts the end byte
+    fn parse_file_sys_ex(message: &[u8]) -> Option<MidiEvent> {
+        let length = message.len() + 1;
+        let mut prefixed = vec![];
+        if length >= 0x80 {
+            prefixed.push(0x80 | (length >> 7) as u8);
+        }
+        prefixed.push((length & 0x7f) as u8);
+        prefixed.extend(message);
+        parse_sys_ex(strip_sys_ex_length(&prefixed)?)
+    }
+
+    #[test]
+    fn bulk_dump_with_the_length_prefix() {
+        // The length takes 2 bytes to store
+        assert_quarter_tone_dump(parse_file_sys_ex(&bulk_dump()));
+    }
+
+    #[test]
+    fn gm_system_on() {
+        assert!(matches!(
+            parse_file_sys_ex(&[0x7e, 0x7f, 0x09, 0x01]),
+            Some(MidiEvent::SystemReset)
+        ));
+        assert!(matches!(
+            parse_file_sys_ex(&[0x7e, 0x10, 0x09, 0x03]),
+            Some(MidiEvent::SystemReset)
+        ));
+    }
+
+    #[test]
+    fn gs_reset() {
+        assert!(matches!(
+            parse_file_sys_ex(&[0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41]),
+            Some(MidiEvent::SystemReset)
+        ));
+    }
+
+    #[test]
+    fn xg_system_on() {
+        assert!(matches!(
+            parse_file_sys_ex(&[0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00]),
+            Some(MidiEvent::SystemReset)
+        ));
+        // Only parameter change messages, with a device number of 1n, turn XG on
+        assert!(parse_file_sys_ex(&[0x43, 0x30, 0x4c, 0x00, 0x00, 0x7e, 0x00]).is_none());
+    }
+
+    #[test]
+    fn gs_drum_part() {
+        // Part 10 comes first in GS part numbering
+        let drum_part = |part, drum_map| {
+            parse_file_sys_ex(&[0x41, 0x10, 0x42, 0x12, 0x40, part, 0x15, drum_map, 0x00])
+        };
+        assert!(matches!(
+            drum_part(0x10, 0x01),
+            Some(MidiEvent::DrumPart {
+                channel: 9,
+                drums: true
+            })
+        ));
+        assert!(matches!(
+            drum_part(0x11, 0x02),
+            Some(MidiEvent::DrumPart {
+                channel: 0,
+                drums: true
+            })
+        ));
+        assert!(matches!(
+            drum_part(0x1a, 0x00),
+            Some(MidiEvent::DrumPart {
+                channel: 10,
+                drums: false
+            })
+        ));
+    }
+
+    #[test]
+    fn length_prefix_is_always_stripped() {
+        // Starts with a byte that looks like the length of a GM System On, but the real length came before it
+        let message = [0x04, 0x7e, 0x7f, 0x09, 0x01];
+        assert!(parse_file_sys_ex(&message).is_none());
+    }
+
+    #[test]
+    fn single_note_change_with_a_bank() {
+        let message = [
+            0x7f, 0x7f, 0x08, 0x07, 0x02, 0x03, 0x02, 69, 69, 0x00, 0x00, 70, 0x7f, 0x7f, 0x7f,
+            0xf7,
+        ];
+        let Some(MidiEvent::RetuneProgram {
+            bank: 2,
+            program: 3,
+            retuning,
+        }) = parse_sys_ex(&message)
+        else {
+            panic!("expected a bank 2 program 3 retune");
+        };
+        // The "no change" note is left out
+        assert_eq!(retuning, Retuning::Keys(vec![(69, 6900.0)]));
+    }
+
+    #[test]
+    fn octave_tuning_channel_mask_bit_order() {
+        let mut message = vec![0x7e, 0x7f, 0x08, 0x08, 0b01, 0b0000001, 0b0000010];
+        message.extend([64; 12]);
+        message[7 + 9] = 64 - 20;
+        let Some(MidiEvent::Retune { channels, retuning }) = parse_sys_ex(&message) else {
+            panic!("expected an octave retune");
+        };
+        // The first byte covers channels 14 and 15, the second 7 to 13 and the last 0 to 6
+        assert_eq!(channels, (1 << 14) | (1 << 7) | (1 << 1));
+        let mut offsets = [0.0; 12];
+        offsets[9] = -20.0;
+        assert_eq!(retuning, Retuning::Octave(offsets));
+    }
+
+    #[test]
+    fn octave_tuning_2_byte_form() {
+        let mut message = vec![0x7f, 0x7f, 0x08, 0x09, 0x03, 0x7f, 0x7f];
+        for note in 0..12 {
+            message.extend(if note == 0 {
+                [0x00, 0x00]
+            } else {
+                [0x40, 0x00]
+            });
+        }
+        let Some(MidiEvent::Retune { channels, retuning }) = parse_sys_ex(&message) else {
+            panic!("expected an octave retune");
+        };
+        assert_eq!(channels, u16::MAX);
+        let mut offsets = [0.0; 12];
+        offsets[0] = -100.0;
+        assert_eq!(retuning, Retuning::Octave(offsets));
+    }
+
+    #[test]
+    fn truncated_octave_tuning_is_ignored() {
+        let message = [0x7e, 0x7f, 0x08, 0x08, 0x03, 0x7f, 0x7f, 64, 64];
+        assert!(parse_sys_ex(&message).is_none());
+    }
 }
@@ -0,0 +1,401 @@
+use crate::curve::{concave, convex};
+use crate::midi::Controller;
+
+/// What a modulator reads its value from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModulatorSource {
+    /// The velocity the note was started with
+    Velocity,
+    /// The note number
+    Key,
+    /// Polyphonic key pressure on the note
+    PolyPressure,
+    ChannelPressure,
+    PitchWheel,
+    /// The pitch bend range in semitones, set by RPN 0
+    PitchWheelSensitivity,
+    /// A MIDI control change number
+    Controller(u8),
+}
+
+/// How a modulator source's value gets shaped before being scaled by the amount
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ModulatorCurve {
+    #[default]
+    Linear,
+    /// Changes slowly at first and quickly at the end, matching how loudness is perceived
+    Concave,
+    /// Changes quickly at first and slowly at the end
+    Convex,
+    /// Jumps from the minimum to the maximum halfway
+    Switch,
+}
+
+/// A modulator source with the SF2 curve, polarity and direction applied to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModulatorInput {
+    pub source: ModulatorSource,
+    pub curve: ModulatorCurve,
+    /// Goes from -1 to 1 instead of 0 to 1
+    pub bipolar: bool,
+    /// Goes from the maximum to the minimum as the source increases
+    pub negative: bool,
+}
+
+impl ModulatorInput {
+    /// A linear, unipolar and positive input
+    pub fn new(source: ModulatorSource) -> Self {
+        Self {
+            source,
+            curve: ModulatorCurve::Linear,
+            bipolar: false,
+            negative: false,
+        }
+    }
+
+    pub fn with_curve(mut self, curve: ModulatorCurve) -> Self {
+        self.curve = curve;
+        self
+    }
+
+    pub fn bipolar(mut self) -> Self {
+        self.bipolar = true;
+        self
+    }
+
+    pub fn negative(mut self) -> Self {
+        self.negative = true;
+        self
+    }
+
+    /// Reads an SF2 modulator source operator, or `None` for sources that aren't supported
+    fn from_sf2(operator: u16) -> Option<Self> {
+        let index = (operator & 0x7f) as u8;
+        let source = if operator & 0x80 != 0 {
+            ModulatorSource::Controller(index)
+        } else {
+            match index {
+                2 => ModulatorSource::Velocity,
+                3 => ModulatorSource::Key,
+                10 => ModulatorSource::PolyPressure,
+                13 => ModulatorSource::ChannelPressure,
+                14 => ModulatorSource::PitchWheel,
+                16 => ModulatorSource::PitchWheelSensitivity,
+                // Includes linking modulators together, which isn't supported
+                _ => return None,
+            }
+        };
+        let curve = match operator >> 10 {
+            0 => ModulatorCurve::Linear,
+            1 => ModulatorCurve::Concave,
+            2 => ModulatorCurve::Convex,
+            3 => ModulatorCurve::Switch,
+            _ => return None,
+        };
+        Some(Self {
+            source,
+            curve,
+            bipolar: operator & 0x200 != 0,
+            negative: operator & 0x100 != 0,
+        })
+    }
+
+    fn value(&self, state: &ModulatorState) -> f32 {
+        let mut value = match self.source {
+            ModulatorSource::Velocity => state.velocity as f32 / 127.0,
+            ModulatorSource::Key => state.key as f32 / 127.0,
+            ModulatorSource::PolyPressure => state.poly_pressure as f32 / 127.0,
+            ModulatorSource::ChannelPressure => state.channel_pressure as f32 / 127.0,
+            // Divided by 16384 so the center is exactly halfway
+            ModulatorSource::PitchWheel => state.pitch_wheel as f32 / 16384.0,
+            ModulatorSource::PitchWheelSensitivity => state.pitch_wheel_sensitivity / 127.0,
+            ModulatorSource::Controller(controller) => {
+                state.controllers[controller as usize & 0x7f] as f32 / 127.0
+            }
+        };
+        if self.negative {
+            value = 1.0 - value;
+        }
+        let curve = |value: f32| match self.curve {
+            ModulatorCurve::Linear => value,
+            ModulatorCurve::Concave => concave(value),
+            ModulatorCurve::Convex => convex(value),
+            ModulatorCurve::Switch => {
+                if value >= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        };
+        if !self.bipolar {
+            return curve(value);
+        }
+        match self.curve {
+            ModulatorCurve::Linear => value * 2.0 - 1.0,
+            ModulatorCurve::Switch => curve(value) * 2.0 - 1.0,
+            // The curve is mirrored around the middle
+            ModulatorCurve::Concave | ModulatorCurve::Convex => {
+                if value >= 0.5 {
+                    curve(value * 2.0 - 1.0)
+                } else {
+                    -curve(1.0 - value * 2.0)
+                }
+            }
+        }
+    }
+}
+
+/// What a modulator changes about the voices it applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModulatorDestination {
+    /// Attenuation in dB
+    Attenuation,
+    /// In cents
+    Pitch,
+    /// From -50 (left) to 50 (right)
+    Pan,
+    /// In cents
+    FilterCutoff,
+    /// Vibrato depth in cents
+    VibratoLfoToPitch,
+}
+
+/// An SF2 style modulator, adding `amount` times its source (and amount source) to a destination
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Modulator {
+    pub source: ModulatorInput,
+    /// Scales the amount, like a modulator controlling another modulator's depth
+    pub amount_source: Option<ModulatorInput>,
+    pub destination: ModulatorDestination,
+    /// In the units of the destination
+    pub amount: f32,
+}
+
+impl Modulator {
+    pub fn new(source: ModulatorInput, destination: ModulatorDestination, amount: f32) -> Self {
+        Self {
+            source,
+            amount_source: None,
+            destination,
+            amount,
+        }
+    }
+
+    pub fn with_amount_source(mut self, amount_source: ModulatorInput) -> Self {
+        self.amount_source = Some(amount_source);
+        self
+    }
+
+    /// Whether adding this modulator replaces the other, which SF2 does for modulators with the same sources and destination
+    pub fn replaces(&self, other: &Modulator) -> bool {
+        self.source == other.source
+            && self.amount_source == other.amount_source
+            && self.destination == other.destination
+    }
+
+    /// Reads an SF2 modulator record, or `None` for modulators with sources, destinations or transforms that aren't supported.
+    /// Amounts are converted from the units of the SF2 generator to the units of the destination.
+    pub(crate) fn from_sf2(
+        source: u16,
+        destination: u16,
+        amount: i16,
+        amount_source: u16,
+        transform: u16,
+    ) -> Option<Self> {
+        // Only the linear transform is supported, not the absolute value one
+        if transform != 0 {
+            return None;
+        }
+        let (destination, scale) = match destination {
+            6 => (ModulatorDestination::VibratoLfoToPitch, 1.0),
+            8 => (ModulatorDestination::FilterCutoff, 1.0),
+            // In 0.1% steps
+            17 => (ModulatorDestination::Pan, 0.1),
+            // In centibels
+            48 => (ModulatorDestination::Attenuation, 0.1),
+            // Coarse tune is in semitones and fine tune in cents
+            51 => (ModulatorDestination::Pitch, 100.0),
+            52 => (ModulatorDestination::Pitch, 1.0),
+            _ => return None,
+        };
+        let mut modulator = Self::new(
+            ModulatorInput::from_sf2(source)?,
+            destination,
+            amount as f32 * scale,
+        );
+        // An amount source with no controller leaves the amount as is
+        if amount_source & 0xff != 0 {
+            modulator.amount_source = Some(ModulatorInput::from_sf2(amount_source)?);
+        }
+        Some(modulator)
+    }
+
+    /// Whether the modulator reads from a source that `changed` matches
+    fn reads(&self, changed: impl Fn(ModulatorSource) -> bool) -> bool {
+        changed(self.source.source)
+            || self
+                .amount_source
+                .is_some_and(|amount_source| changed(amount_source.source))
+    }
+
+    fn value(&self, state: &ModulatorState) -> f32 {
+        let amount_source = self
+            .amount_source
+            .map_or(1.0, |amount_source| amount_source.value(state));
+        self.source.value(state) * amount_source * self.amount
+    }
+}
+
+/// The modulators every track starts out with, the SF2 default modulators.
+/// The reverb and chorus send ones are left out since there are no effects to send to.
+pub fn default_modulators() -> Vec<Modulator> {
+    let controller =
+        |controller: Controller| ModulatorInput::new(ModulatorSource::Controller(controller as u8));
+    vec![
+        Modulator::new(
+            ModulatorInput::new(ModulatorSource::Velocity)
+                .with_curve(ModulatorCurve::Concave)
+                .negative(),
+            ModulatorDestination::Attenuation,
+            96.0,
+        ),
+        Modulator::new(
+            ModulatorInput::new(ModulatorSource::Velocity).negative(),
+            ModulatorDestination::FilterCutoff,
+            -2400.0,
+        )
+        .with_amount_source(
+            ModulatorInput::new(ModulatorSource::Velocity).with_curve(ModulatorCurve::Switch),
+        ),
+        Modulator::new(
+            ModulatorInput::new(ModulatorSource::ChannelPressure),
+            ModulatorDestination::VibratoLfoToPitch,
+            50.0,
+        ),
+        Modulator::new(
+            controller(Controller::Modulation),
+            ModulatorDestination::VibratoLfoToPitch,
+            50.0,
+        ),
+        Modulator::new(
+            controller(Controller::Volume)
+                .with_curve(ModulatorCurve::Concave)
+                .negative(),
+            ModulatorDestination::Attenuation,
+            96.0,
+        ),
+        Modulator::new(
+            controller(Controller::Pan).bipolar(),
+            ModulatorDestination::Pan,
+            50.0,
+        ),
+        Modulator::new(
+            controller(Controller::Expression)
+                .with_curve(ModulatorCurve::Concave)
+                .negative(),
+            ModulatorDestination::Attenuation,
+            96.0,
+        ),
+        // A full bend moves the pitch by the pitch bend range in semitones
+        Modulator::new(
+            ModulatorInput::new(ModulatorSource::PitchWheel).bipolar(),
+            ModulatorDestination::Pitch,
+            12700.0,
+        )
+        .with_amount_source(ModulatorInput::new(ModulatorSource::PitchWheelSensitivity)),
+    ]
+}
+
+/// Adds modulators to a list, replacing the ones they're identical to like SF2 does
+pub fn override_modulators(modulators: &mut Vec<Modulator>, overrides: &[Modulator]) {
+    for modulator in overrides {
+        modulators.retain(|existing| !modulator.replaces(existing));
+        modulators.push(*modulator);
+    }
+}
+
+/// The values modulator sources read for a voice
+pub struct ModulatorState<'a> {
+    pub velocity: u8,
+    pub key: u8,
+    pub poly_pressure: u8,
+    pub channel_pressure: u8,
+    /// 14 bits, centered on 8192
+    pub pitch_wheel: u16,
+    /// In semitones
+    pub pitch_wheel_sensitivity: f32,
+    pub controllers: &'a [u8; 128],
+}
+
+/// The summed modulator values for each destination
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ModulatorOutputs {
+    /// In dB
+    pub attenuation: f32,
+    /// In cents
+    pub pitch: f32,
+    /// From -50 (left) to 50 (right)
+    pub pan: f32,
+    /// In cents
+    pub filter_cutoff: f32,
+    /// In cents
+    pub vibrato_lfo_to_pitch: f32,
+}
+
+/// A voice's modulators along with their latest values, so only the ones reading a changed source need evaluating again
+pub struct ModulatorCache {
+    modulators: Vec<(Modulator, f32)>,
+    outputs: ModulatorOutputs,
+}
+
+impl ModulatorCache {
+    pub fn new(modulators: Vec<Modulator>, state: &ModulatorState) -> Self {
+        let mut cache = Self {
+            modulators: modulators
+                .into_iter()
+                .map(|modulator| (modulator, modulator.value(state)))
+                .collect(),
+            outputs: ModulatorOutputs::default(),
+        };
+        cache.sum_outputs();
+        cache
+    }
+
+    /// Evaluates the modulators reading a source that `changed` matches again, returning whether there were any
+    pub fn update(
+        &mut self,
+        state: &ModulatorState,
+        changed: impl Fn(ModulatorSource) -> bool,
+    ) -> bool {
+        let mut updated = false;
+        for (modulator, value) in &mut self.modulators {
+            if modulator.reads(&changed) {
+                *value = modulator.value(state);
+                updated = true;
+            }
+        }
+        if updated {
+            self.sum_outputs();
+        }
+        updated
+    }
+
+    pub fn outputs(&self) -> &ModulatorOutputs {
+        &self.outputs
+    }
+
+    fn sum_outputs(&mut self) {
+        let mut outputs = ModulatorOutputs::default();
+        for &(modulator, value) in &self.modulators {
+            match modulator.destination {
+                ModulatorDestination::Attenuation => outputs.attenuation += value,
+                ModulatorDestination::Pitch => outputs.pitch += value,
+                ModulatorDestination::Pan => outputs.pan += value,
+                ModulatorDestination::FilterCutoff => outputs.filter_cutoff += value,
+                ModulatorDestination::VibratoLfoToPitch => outputs.vibrato_lfo_to_pitch += value,
+            }
+        }
+        self.outputs = outputs;
+    }
+}
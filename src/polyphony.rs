@@ -0,0 +1,71 @@
+use std::cmp::Ordering;
+
+use bevy::platform::collections::HashSet;
+
+/// Which voice gets faded out to make room once too many are playing.
+/// Voices that are already releasing are always taken first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VoiceStealingPolicy {
+    /// The voice that started the longest ago
+    #[default]
+    Oldest,
+    /// The voice with the lowest current level
+    Quietest,
+    /// A voice on the channel with the lowest priority, oldest first
+    LowestPriority,
+    /// A voice playing the same note on the same channel as the newest voice, otherwise the oldest
+    SameNoteFirst,
+}
+
+/// What a note on does to a key that's already held down on the same channel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RetriggerPolicy {
+    /// Releases the held voice and starts a new one. The held voice's note off is swallowed so it doesn't end the new one.
+    #[default]
+    Retrigger,
+    /// Starts a new voice alongside the held one, note offs release the oldest first
+    Stack,
+    /// Keeps the held voice and drops the new note
+    Ignore,
+}
+
+/// What the stealing policies need to know about a playing voice
+pub struct StealCandidate {
+    /// Increases with every voice started, so lower ids are older
+    pub id: u64,
+    pub released: bool,
+    /// Voices that only just started haven't been heard yet, so they're taken last
+    pub just_started: bool,
+    /// Linear gain the voice is currently sounding at
+    pub level: f32,
+    pub channel_priority: u8,
+    /// Whether the voice plays the same note on the same channel as the newest voice
+    pub same_note: bool,
+}
+
+impl VoiceStealingPolicy {
+    /// Picks the ids of the `count` voices to steal
+    pub fn choose(self, mut candidates: Vec<StealCandidate>, count: usize) -> HashSet<u64> {
+        candidates.sort_by(|a, b| {
+            b.released
+                .cmp(&a.released)
+                .then_with(|| a.just_started.cmp(&b.just_started))
+                .then_with(|| self.compare(a, b))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|candidate| candidate.id)
+            .collect()
+    }
+
+    fn compare(self, a: &StealCandidate, b: &StealCandidate) -> Ordering {
+        match self {
+            VoiceStealingPolicy::Oldest => Ordering::Equal,
+            VoiceStealingPolicy::Quietest => a.level.total_cmp(&b.level),
+            VoiceStealingPolicy::LowestPriority => a.channel_priority.cmp(&b.channel_priority),
+            VoiceStealingPolicy::SameNoteFirst => b.same_note.cmp(&a.same_note),
+        }
+    }
+}
@@ -0,0 +1,652 @@
+use std::sync::Arc;
+
+use bevy::platform::collections::HashMap;
+use rustysynth::{InstrumentRegion, PresetRegion, SampleHeader, SoundFont};
+
+use crate::modulator::{Modulator, override_modulators};
+
+/// The SF2 bank that holds drum kits
+pub const PERCUSSION_BANK: u8 = 128;
+
+pub struct SoundFontBank {
+    soundfont: Arc<SoundFont>,
+    preset_index: HashMap<(u8, u8), usize>,
+    /// Empty unless read from the SoundFont file with [`SoundFontBank::with_modulators`]
+    zones: RawZones,
+}
+
+impl SoundFontBank {
+    pub fn new(soundfont: Arc<SoundFont>) -> Self {
+        let preset_index = soundfont
+            .get_presets()
+            .iter()
+            .enumerate()
+            .map(|(index, preset)| {
+                (
+                    (
+                        preset.get_bank_number() as u8,
+                        preset.get_patch_number() as u8,
+                    ),
+                    index,
+                )
+            })
+            .collect();
+        Self {
+            soundfont,
+            preset_index,
+            zones: RawZones::default(),
+        }
+    }
+
+    /// Reads the preset and instrument zone modulators, and the fixed key and velocity generators,
+    /// from the SoundFont file the bank was loaded from, since `rustysynth` skips over them.
+    /// Leaves them out if the file can't be read.
+    pub fn with_modulators(mut self, soundfont_bytes: &[u8]) -> Self {
+        self.zones = RawZones::read(soundfont_bytes).unwrap_or_default();
+        self
+    }
+
+    pub fn wave_data(&self) -> &[i16] {
+        self.soundfont.get_wave_data()
+    }
+
+    /// Falls back to the general MIDI bank when the SoundFont doesn't have the preset, like GM players do
+    fn find_preset(&self, bank_number: u8, patch_number: u8) -> Option<usize> {
+        let fallback = if bank_number == PERCUSSION_BANK {
+            (PERCUSSION_BANK, 0)
+        } else {
+            (0, patch_number)
+        };
+        self.preset_index
+            .get(&(bank_number, patch_number))
+            .or_else(|| self.preset_index.get(&fallback))
+            .copied()
+    }
+
+    pub fn get_regions(
+        &self,
+        note: i32,
+        velocity: i32,
+        bank_number: u8,
+        patch_number: u8,
+    ) -> Option<Vec<SoundFontRegion<'_>>> {
+        let preset_index = self.find_preset(bank_number, patch_number)?;
+        let preset = &self.soundfont.get_presets()[preset_index];
+        let preset_regions = preset
+            .get_regions()
+            .iter()
+            .enumerate()
+            .filter(|(_, region)| region.contains(note, velocity));
+        let regions = preset_regions.flat_map(|(preset_region_index, preset_region)| {
+            let instrument_index = preset_region.get_instrument_id();
+            let instrument = &self.soundfont.get_instruments()[instrument_index];
+            instrument
+                .get_regions()
+                .iter()
+                .enumerate()
+                .filter(|(_, region)| region.contains(note, velocity))
+                .map(move |(instrument_region_index, instrument_region)| {
+                    let instrument_zone = self
+                        .zones
+                        .instrument(instrument_index, instrument_region_index);
+                    SoundFontRegion {
+                        id: RegionId {
+                            preset: preset_index,
+                            preset_region: preset_region_index,
+                            instrument: instrument_index,
+                            instrument_region: instrument_region_index,
+                        },
+                        preset: preset_region,
+                        instrument: instrument_region,
+                        sample: &self.soundfont.get_sample_headers()
+                            [instrument_region.get_sample_id()],
+                        preset_modulators: &self
+                            .zones
+                            .preset(preset_index, preset_region_index)
+                            .modulators,
+                        instrument_modulators: &instrument_zone.modulators,
+                        fixed_key: instrument_zone.fixed_key,
+                        fixed_velocity: instrument_zone.fixed_velocity,
+                    }
+                })
+        });
+        Some(regions.collect())
+    }
+}
+
+/// A matching preset zone, instrument zone and sample for a note.
+/// Global zones are already folded into both zones, and the getters merge the two following the SF2 rules:
+/// preset zone values are added on top of the instrument zone values.
+#[derive(Clone, Copy)]
+pub struct SoundFontRegion<'a> {
+    pub id: RegionId,
+    pub preset: &'a PresetRegion,
+    pub instrument: &'a InstrumentRegion,
+    pub sample: &'a SampleHeader,
+    pub preset_modulators: &'a [Modulator],
+    pub instrument_modulators: &'a [Modulator],
+    /// From the instrument zone's keynum generator, the key every note plays as
+    pub fixed_key: Option<u8>,
+    /// From the instrument zone's velocity generator, the velocity every note plays with
+    pub fixed_velocity: Option<u8>,
+}
+
+/// Which preset zone and instrument zone a region comes from, for telling whether two notes play the same zones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionId {
+    preset: usize,
+    preset_region: usize,
+    instrument: usize,
+    instrument_region: usize,
+}
+
+impl SoundFontRegion<'_> {
+    /// The zones' modulators on top of `defaults`.
+    /// Instrument modulators replace identical default ones, while preset modulators add on top like preset generators do.
+    pub fn modulators(&self, defaults: &[Modulator]) -> Vec<Modulator> {
+        let mut modulators = defaults.to_vec();
+        override_modulators(&mut modulators, self.instrument_modulators);
+        modulators.extend_from_slice(self.preset_modulators);
+        modulators
+    }
+
+    // Sample addresses, the root key and the fixed key and velocity are instrument-only generators
+
+    /// The key the zone plays `note` as, for its pitch, modulators and envelope key scaling
+    pub fn key(&self, note: i32) -> i32 {
+        self.fixed_key.map_or(note, i32::from)
+    }
+
+    /// The velocity the zone plays `velocity` as, for its modulators
+    pub fn velocity(&self, velocity: i32) -> i32 {
+        self.fixed_velocity.map_or(velocity, i32::from)
+    }
+
+    pub fn sample_start(&self) -> i32 {
+        self.instrument.get_sample_start()
+    }
+
+    pub fn sample_end(&self) -> i32 {
+        self.instrument.get_sample_end()
+    }
+
+    pub fn sample_start_loop(&self) -> i32 {
+        self.instrument.get_sample_start_loop()
+    }
+
+    pub fn sample_end_loop(&self) -> i32 {
+        self.instrument.get_sample_end_loop()
+    }
+
+    pub fn loop_mode(&self) -> LoopMode {
+        // Some SoundFonts set a loop mode on samples without a usable loop
+        if self.sample_end_loop() <= self.sample_start_loop() {
+            return LoopMode::NoLoop;
+        }
+        match self.instrument.get_sample_modes() {
+            rustysynth::LoopMode::NoLoop => LoopMode::NoLoop,
+            rustysynth::LoopMode::Continuous => LoopMode::Continuous,
+            rustysynth::LoopMode::LoopUntilNoteOff => LoopMode::LoopUntilRelease,
+        }
+    }
+
+    /// Starting a sample cuts off the other samples on the channel with the same class, unless it's 0
+    pub fn exclusive_class(&self) -> i32 {
+        self.instrument.get_exclusive_class()
+    }
+
+    pub fn root_key(&self) -> i32 {
+        self.instrument.get_root_key()
+    }
+
+    /// In semitones
+    pub fn coarse_tune(&self) -> i32 {
+        self.instrument.get_coarse_tune() + self.preset.get_coarse_tune()
+    }
+
+    /// In cents, including the sample's pitch correction
+    pub fn fine_tune(&self) -> i32 {
+        self.instrument.get_fine_tune() + self.preset.get_fine_tune()
+    }
+
+    /// In cents per key
+    pub fn scale_tuning(&self) -> i32 {
+        self.instrument.get_scale_tuning() + self.preset.get_scale_tuning()
+    }
+
+    /// The pitch of `note` relative to the sample's recorded pitch, in cents
+    pub fn pitch_offset(&self, note: i32) -> f32 {
+        ((note - self.root_key()) * self.scale_tuning()
+            + self.coarse_tune() * 100
+            + self.fine_tune()) as f32
+    }
+
+    /// In dB
+    pub fn initial_attenuation(&self) -> f32 {
+        (self.instrument.get_initial_attenuation() + self.preset.get_initial_attenuation())
+            .clamp(0.0, 144.0)
+    }
+
+    /// From -50 (left) to 50 (right)
+    pub fn pan(&self) -> f32 {
+        (self.instrument.get_pan() + self.preset.get_pan()).clamp(-50.0, 50.0)
+    }
+
+    /// In Hz
+    pub fn initial_filter_cutoff(&self) -> f32 {
+        self.instrument.get_initial_filter_cutoff_frequency()
+            * self.preset.get_initial_filter_cutoff_frequency()
+    }
+
+    /// Resonance peak height in dB
+    pub fn initial_filter_q(&self) -> f32 {
+        (self.instrument.get_initial_filter_q() + self.preset.get_initial_filter_q())
+            .clamp(0.0, 96.0)
+    }
+
+    /// In cents at full modulation LFO excursion
+    pub fn modulation_lfo_to_pitch(&self) -> i32 {
+        self.instrument.get_modulation_lfo_to_pitch() + self.preset.get_modulation_lfo_to_pitch()
+    }
+
+    /// In cents at full vibrato LFO excursion
+    pub fn vibrato_lfo_to_pitch(&self) -> i32 {
+        self.instrument.get_vibrato_lfo_to_pitch() + self.preset.get_vibrato_lfo_to_pitch()
+    }
+
+    /// In cents at full modulation envelope level
+    pub fn modulation_envelope_to_pitch(&self) -> i32 {
+        self.instrument.get_modulation_envelope_to_pitch()
+            + self.preset.get_modulation_envelope_to_pitch()
+    }
+
+    /// In cents at full modulation LFO excursion
+    pub fn modulation_lfo_to_filter_cutoff(&self) -> i32 {
+        self.instrument
+            .get_modulation_lfo_to_filter_cutoff_frequency()
+            + self.preset.get_modulation_lfo_to_filter_cutoff_frequency()
+    }
+
+    /// In cents at full modulation envelope level
+    pub fn modulation_envelope_to_filter_cutoff(&self) -> i32 {
+        self.instrument
+            .get_modulation_envelope_to_filter_cutoff_frequency()
+            + self
+                .preset
+                .get_modulation_envelope_to_filter_cutoff_frequency()
+    }
+
+    /// Attenuation in dB at full modulation LFO excursion
+    pub fn modulation_lfo_to_volume(&self) -> f32 {
+        self.instrument.get_modulation_lfo_to_volume() + self.preset.get_modulation_lfo_to_volume()
+    }
+
+    /// In seconds
+    pub fn delay_modulation_lfo(&self) -> f32 {
+        self.instrument.get_delay_modulation_lfo() * self.preset.get_delay_modulation_lfo()
+    }
+
+    /// In Hz
+    pub fn frequency_modulation_lfo(&self) -> f32 {
+        self.instrument.get_frequency_modulation_lfo() * self.preset.get_frequency_modulation_lfo()
+    }
+
+    /// In seconds
+    pub fn delay_vibrato_lfo(&self) -> f32 {
+        self.instrument.get_delay_vibrato_lfo() * self.preset.get_delay_vibrato_lfo()
+    }
+
+    /// In Hz
+    pub fn frequency_vibrato_lfo(&self) -> f32 {
+        self.instrument.get_frequency_vibrato_lfo() * self.preset.get_frequency_vibrato_lfo()
+    }
+
+    /// In seconds
+    pub fn delay_modulation_envelope(&self) -> f32 {
+        self.instrument.get_delay_modulation_envelope()
+            * self.preset.get_delay_modulation_envelope()
+    }
+
+    /// In seconds
+    pub fn attack_modulation_envelope(&self) -> f32 {
+        self.instrument.get_attack_modulation_envelope()
+            * self.preset.get_attack_modulation_envelope()
+    }
+
+    /// In seconds
+    pub fn hold_modulation_envelope(&self) -> f32 {
+        self.instrument.get_hold_modulation_envelope() * self.preset.get_hold_modulation_envelope()
+    }
+
+    /// In seconds
+    pub fn decay_modulation_envelope(&self) -> f32 {
+        self.instrument.get_decay_modulation_envelope()
+            * self.preset.get_decay_modulation_envelope()
+    }
+
+    /// Decrease from full level in percent
+    pub fn sustain_modulation_envelope(&self) -> f32 {
+        self.instrument.get_sustain_modulation_envelope()
+            + self.preset.get_sustain_modulation_envelope()
+    }
+
+    /// In seconds
+    pub fn release_modulation_envelope(&self) -> f32 {
+        self.instrument.get_release_modulation_envelope()
+            * self.preset.get_release_modulation_envelope()
+    }
+
+    /// In timecents per key
+    pub fn key_number_to_modulation_envelope_hold(&self) -> i32 {
+        self.instrument.get_key_number_to_modulation_envelope_hold()
+            + self.preset.get_key_number_to_modulation_envelope_hold()
+    }
+
+    /// In timecents per key
+    pub fn key_number_to_modulation_envelope_decay(&self) -> i32 {
+        self.instrument
+            .get_key_number_to_modulation_envelope_decay()
+            + self.preset.get_key_number_to_modulation_envelope_decay()
+    }
+
+    /// In seconds
+    pub fn delay_volume_envelope(&self) -> f32 {
+        self.instrument.get_delay_volume_envelope() * self.preset.get_delay_volume_envelope()
+    }
+
+    /// In seconds
+    pub fn attack_volume_envelope(&self) -> f32 {
+        self.instrument.get_attack_volume_envelope() * self.preset.get_attack_volume_envelope()
+    }
+
+    /// In seconds
+    pub fn hold_volume_envelope(&self) -> f32 {
+        self.instrument.get_hold_volume_envelope() * self.preset.get_hold_volume_envelope()
+    }
+
+    /// In seconds
+    pub fn decay_volume_envelope(&self) -> f32 {
+        self.instrument.get_decay_volume_envelope() * self.preset.get_decay_volume_envelope()
+    }
+
+    /// Attenuation in dB
+    pub fn sustain_volume_envelope(&self) -> f32 {
+        self.instrument.get_sustain_volume_envelope() + self.preset.get_sustain_volume_envelope()
+    }
+
+    /// In seconds
+    pub fn release_volume_envelope(&self) -> f32 {
+        self.instrument.get_release_volume_envelope() * self.preset.get_release_volume_envelope()
+    }
+
+    /// In timecents per key
+    pub fn key_number_to_volume_envelope_hold(&self) -> i32 {
+        self.instrument.get_key_number_to_volume_envelope_hold()
+            + self.preset.get_key_number_to_volume_envelope_hold()
+    }
+
+    /// In timecents per key
+    pub fn key_number_to_volume_envelope_decay(&self) -> i32 {
+        self.instrument.get_key_number_to_volume_envelope_decay()
+            + self.preset.get_key_number_to_volume_envelope_decay()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopMode {
+    NoLoop,
+    Continuous,
+    LoopUntilRelease,
+}
+
+/// What `rustysynth` skips over in every preset and instrument zone, with global zones already folded in.
+/// Indexed like the presets and instruments of the `SoundFont`, and then like their regions.
+#[derive(Default)]
+struct RawZones {
+    presets: Vec<Vec<Zone>>,
+    instruments: Vec<Vec<Zone>>,
+}
+
+struct Zone {
+    modulators: Vec<Modulator>,
+    /// Ignored on preset zones, since the SF2 spec only allows it on instrument zones
+    fixed_key: Option<u8>,
+    /// Ignored on preset zones, since the SF2 spec only allows it on instrument zones
+    fixed_velocity: Option<u8>,
+}
+
+static EMPTY_ZONE: Zone = Zone {
+    modulators: Vec::new(),
+    fixed_key: None,
+    fixed_velocity: None,
+};
+
+/// The generator that ends every preset zone apart from the global one
+const INSTRUMENT_GENERATOR: u16 = 41;
+/// The generator that forces the key a zone plays its notes as
+const KEYNUM_GENERATOR: u16 = 46;
+/// The generator that forces the velocity a zone plays its notes with
+const VELOCITY_GENERATOR: u16 = 47;
+/// The generator that ends every instrument zone apart from the global one
+const SAMPLE_ID_GENERATOR: u16 = 53;
+
+impl RawZones {
+    /// Reads the `pdta` list of an SF2 file, or `None` if it's malformed
+    fn read(soundfont_bytes: &[u8]) -> Option<Self> {
+        let (id, sfbk) = riff_chunks(soundfont_bytes).next()?;
+        if id != b"RIFF" || sfbk.get(..4)? != b"sfbk" {
+            return None;
+        }
+        let pdta = riff_chunks(&sfbk[4..])
+            .find(|(id, data)| *id == b"LIST" && data.starts_with(b"pdta"))?
+            .1;
+        let sub_chunks = riff_chunks(&pdta[4..]).collect::<HashMap<_, _>>();
+        let sub_chunk = |id: &[u8; 4]| sub_chunks.get(id.as_slice()).copied();
+        Some(Self {
+            // Preset headers are 38 bytes, with the index of their first zone at byte 24
+            presets: read_zones(
+                sub_chunk(b"phdr")?,
+                38,
+                24,
+                sub_chunk(b"pbag")?,
+                sub_chunk(b"pmod")?,
+                sub_chunk(b"pgen")?,
+                INSTRUMENT_GENERATOR,
+            )?,
+            // Instrument headers are 22 bytes, with the index of their first zone at byte 20
+            instruments: read_zones(
+                sub_chunk(b"inst")?,
+                22,
+                20,
+                sub_chunk(b"ibag")?,
+                sub_chunk(b"imod")?,
+                sub_chunk(b"igen")?,
+                SAMPLE_ID_GENERATOR,
+            )?,
+        })
+    }
+
+    fn preset(&self, preset_index: usize, region_index: usize) -> &Zone {
+        zone(&self.presets, preset_index, region_index)
+    }
+
+    fn instrument(&self, instrument_index: usize, region_index: usize) -> &Zone {
+        zone(&self.instruments, instrument_index, region_index)
+    }
+}
+
+fn zone(zones: &[Vec<Zone>], index: usize, region_index: usize) -> &Zone {
+    zones
+        .get(index)
+        .and_then(|regions| regions.get(region_index))
+        .unwrap_or(&EMPTY_ZONE)
+}
+
+/// The ID and data of each chunk in a RIFF chunk list
+fn riff_chunks(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let id = data.get(..4)?;
+        let size = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize;
+        let chunk = data.get(8..8 + size)?;
+        // Chunks are padded to an even size
+        data = data.get(8 + size + size % 2..).unwrap_or_default();
+        Some((id, chunk))
+    })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// For every preset or instrument, the modulators and fixed key and velocity of the zones `rustysynth` makes regions out of.
+/// The last header and zone are terminators.
+fn read_zones(
+    headers: &[u8],
+    header_size: usize,
+    zone_offset: usize,
+    zones: &[u8],
+    modulators: &[u8],
+    generators: &[u8],
+    last_generator: u16,
+) -> Option<Vec<Vec<Zone>>> {
+    // Zones are 4 bytes, the index of their first generator and of their first modulator
+    let zone_start =
+        |zone: usize| Some((read_u16(zones, zone * 4)?, read_u16(zones, zone * 4 + 2)?));
+    let zone_modulators = |zone: usize| {
+        let (_, start) = zone_start(zone)?;
+        let (_, end) = zone_start(zone + 1)?;
+        // Modulators are 10 bytes: source, destination, amount, amount source and transform
+        let modulators = modulators.get(start as usize * 10..end as usize * 10)?;
+        Some(
+            modulators
+                .chunks_exact(10)
+                .filter_map(|modulator| {
+                    let field = |index: usize| read_u16(modulator, index * 2).unwrap_or_default();
+                    Modulator::from_sf2(field(0), field(1), field(2) as i16, field(3), field(4))
+                })
+                .collect::<Vec<_>>(),
+        )
+    };
+    // Generators are 4 bytes, their type and then their amount. The last one of a type counts, and -1 means unset.
+    let zone_generator = |zone: usize, generator: u16| {
+        let (start, _) = zone_start(zone)?;
+        let (end, _) = zone_start(zone + 1)?;
+        let generators = generators.get(start as usize * 4..end as usize * 4)?;
+        Some(
+            generators
+                .chunks_exact(4)
+                .filter(|amount| read_u16(amount, 0) == Some(generator))
+                .filter_map(|amount| read_u16(amount, 2))
+                .next_back(),
+        )
+    };
+    let is_global = |zone: usize| {
+        let (start, _) = zone_start(zone)?;
+        let (end, _) = zone_start(zone + 1)?;
+        // Generators are 4 bytes, starting with their type
+        let last = end.checked_sub(1).filter(|&last| last >= start);
+        Some(
+            last.is_none_or(|last| read_u16(generators, last as usize * 4) != Some(last_generator)),
+        )
+    };
+
+    let first_zones = headers
+        .chunks_exact(header_size)
+        .map(|header| read_u16(header, zone_offset).map(usize::from))
+        .collect::<Option<Vec<_>>>()?;
+    first_zones
+        .windows(2)
+        .map(|window| {
+            let (mut start, end) = (window[0], window[1]);
+            let (mut global, mut global_key, mut global_velocity) = (vec![], None, None);
+            if start < end && is_global(start)? {
+                global = zone_modulators(start)?;
+                global_key = zone_generator(start, KEYNUM_GENERATOR)?;
+                global_velocity = zone_generator(start, VELOCITY_GENERATOR)?;
+                start += 1;
+            }
+            (start..end)
+                .map(|zone| {
+                    let mut modulators = global.clone();
+                    override_modulators(&mut modulators, &zone_modulators(zone)?);
+                    Some(Zone {
+                        modulators,
+                        fixed_key: fixed_value(
+                            zone_generator(zone, KEYNUM_GENERATOR)?.or(global_key),
+                        ),
+                        fixed_velocity: fixed_value(
+                            zone_generator(zone, VELOCITY_GENERATOR)?.or(global_velocity),
+                        ),
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// A keynum or velocity generator amount, which only counts from 0 to 127
+fn fixed_value(amount: Option<u16>) -> Option<u8> {
+    u8::try_from(amount? as i16)
+        .ok()
+        .filter(|&value| value < 128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Little endian bytes of each value
+    fn words(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn reads_fixed_key_and_velocity() {
+        // One instrument and the terminator, with their first zone at byte 20
+        let mut headers = vec![0; 44];
+        headers[42] = 4;
+        // A global zone and three more, each the index of its first generator and modulator
+        let zones = words(&[0, 0, 1, 0, 3, 0, 5, 0, 6, 0]);
+        let generators = words(&[
+            // The global zone fixes the key to 60
+            KEYNUM_GENERATOR,
+            60,
+            // Keeps the global key and fixes the velocity to 100
+            VELOCITY_GENERATOR,
+            100,
+            SAMPLE_ID_GENERATOR,
+            0,
+            // Unsets the key with -1
+            KEYNUM_GENERATOR,
+            0xffff,
+            SAMPLE_ID_GENERATOR,
+            0,
+            // Keeps everything
+            SAMPLE_ID_GENERATOR,
+            0,
+        ]);
+        let instruments = read_zones(
+            &headers,
+            22,
+            20,
+            &zones,
+            &[],
+            &generators,
+            SAMPLE_ID_GENERATOR,
+        )
+        .unwrap();
+        let fixed = instruments[0]
+            .iter()
+            .map(|zone| (zone.fixed_key, zone.fixed_velocity))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fixed,
+            [(Some(60), Some(100)), (None, None), (Some(60), None)]
+        );
+    }
+}
@@ -1,43 +1,166 @@
 use std::collections::VecDeque;
+use std::f32::consts::{FRAC_PI_2, SQRT_2};
 use std::io::Cursor;
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+use std::sync::atomic::{self, AtomicU64};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use bevy::audio::{CpalSample, Sample, Source};
 use bevy::platform::collections::{HashMap, HashSet};
-use bevy::{audio::Source, prelude::*};
-use num_enum::TryFromPrimitive;
-use rustysynth::{SampleHeader, SoundFont};
+use bevy::prelude::*;
+use rustysynth::SoundFont;
 
 use crate::Note;
-use crate::midi::{MidiEvent, MidiTrack};
+use crate::envelope::{ModulationEnvelope, VolumeEnvelope, decibels_to_gain};
+use crate::filter::LowPassFilter;
+use crate::interpolation::InterpolationMode;
+use crate::lfo::Lfo;
+use crate::midi::{Controller, MidiEvent, MidiTrack, RegisteredParameter};
+use crate::modulator::{
+    Modulator, ModulatorCache, ModulatorSource, ModulatorState, default_modulators,
+    override_modulators,
+};
+use crate::polyphony::{RetriggerPolicy, StealCandidate, VoiceStealingPolicy};
+use crate::soundfont::{LoopMode, PERCUSSION_BANK, RegionId, SoundFontBank, SoundFontRegion};
+use crate::tuning::TuningTable;
+
+const DEFAULT_SAMPLES_PER_SECOND: f64 = 44100.0;
+
+/// Mixed levels above this get smoothly compressed by the master soft clipper
+const SOFT_CLIP_THRESHOLD: f32 = 0.8;
+/// In seconds, how long a portamento glide takes with CC5 all the way up
+const MAX_PORTAMENTO_TIME: f32 = 4.0;
+/// In seconds, how long a portamento glide takes until CC5 sets it, so turning portamento on is audible by itself
+const DEFAULT_PORTAMENTO_TIME: f32 = 0.1;
+/// The CC0 value XG files select drum kits with
+const XG_DRUM_BANK_SELECT: u8 = 127;
+/// The CC0 value GS and GM2 files select drum kits with
+const GM2_DRUM_BANK_SELECT: u8 = 120;
+/// Attenuation in dB the soft pedal applies to new notes
+const SOFT_PEDAL_ATTENUATION: f32 = 3.0;
+/// In cents, how much the soft pedal lowers the filter cutoff of new notes
+const SOFT_PEDAL_CUTOFF: f32 = -1200.0;
+
+/// A sample type the mix can be handed to Bevy in, `i16` or `f32`
+pub trait MidiSample: Sample + TypePath + Send + Sync {
+    fn from_f32(sample: f32) -> Self;
+}
+
+impl MidiSample for i16 {
+    fn from_f32(sample: f32) -> Self {
+        i16::from_sample(sample)
+    }
+}
 
+impl MidiSample for f32 {
+    fn from_f32(sample: f32) -> Self {
+        sample
+    }
+}
+
+/// Plays MIDI tracks through a SoundFont, handing Bevy `i16` samples unless `S` says otherwise
 #[derive(Asset, TypePath)]
-pub struct MidiAudio {
+pub struct MidiAudio<S: MidiSample = i16> {
     tracks: HashMap<MidiAudioTrackHandle, MidiAudioTrack>,
     soundfont: SoundFontBank,
     num_audio_channels: u16,
     current_audio_channel: u16,
     samples_per_second: f64,
-    buffer: Arc<Mutex<VecDeque<i16>>>,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
     buffer_events: Vec<(Instant, MidiBufferMessage)>,
     buffer_event_now: Instant,
+    interpolation: InterpolationMode,
+    /// Across all tracks
+    max_voices: Option<usize>,
+    voice_stealing: VoiceStealingPolicy,
+    /// Linear gain everything is mixed at
+    master_volume: f32,
+    /// In Hz, the frequency of A4
+    master_tuning: f32,
+    sample: PhantomData<S>,
 }
 
-impl MidiAudio {
+impl<S: MidiSample> MidiAudio<S> {
+    /// SoundFont modulators only get read by [`MidiAudio::from_bytes`], since `SoundFont` leaves them out
     pub fn new(soundfont: Arc<SoundFont>) -> Self {
+        Self::from_soundfont_bank(SoundFontBank::new(soundfont))
+    }
+
+    fn from_soundfont_bank(soundfont: SoundFontBank) -> Self {
         Self {
             tracks: HashMap::new(),
-            soundfont: SoundFontBank::new(soundfont),
+            soundfont,
             num_audio_channels: 2,
             current_audio_channel: 0,
-            samples_per_second: 44100.0,
+            samples_per_second: DEFAULT_SAMPLES_PER_SECOND,
             buffer: Arc::new(Mutex::new(VecDeque::new())),
             buffer_events: vec![],
             buffer_event_now: Instant::now(),
+            interpolation: InterpolationMode::default(),
+            max_voices: None,
+            voice_stealing: VoiceStealingPolicy::default(),
+            master_volume: 1.0,
+            master_tuning: 440.0,
+            sample: PhantomData,
+        }
+    }
+
+    /// Sets the sample rate (e.g. 22050, 44100 or 48000) and the channels of the audio output.
+    /// Defaults to 44100 Hz stereo.
+    pub fn with_output_format(
+        mut self,
+        samples_per_second: NonZeroU32,
+        output_channels: OutputChannels,
+    ) -> Self {
+        self.samples_per_second = samples_per_second.get() as f64;
+        self.num_audio_channels = output_channels.count();
+        self.current_audio_channel = 0;
+        self.buffer.lock().unwrap().clear();
+        for track in self.tracks.values_mut() {
+            track.set_samples_per_second(self.samples_per_second);
         }
+        self
     }
 
-    pub fn add_track(&mut self, midi_track: MidiAudioTrack) -> MidiAudioTrackHandle {
+    /// Sets how every track reads between wave samples, unless the track overrides it
+    pub fn with_interpolation(mut self, interpolation: InterpolationMode) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    /// Caps how many voices can play at once across all tracks, fading out voices picked by the stealing policy past that
+    pub fn with_max_voices(mut self, max_voices: usize) -> Self {
+        self.max_voices = Some(max_voices);
+        self
+    }
+
+    /// Sets which voices get stolen once a voice limit is hit, unless the track overrides it
+    pub fn with_voice_stealing(mut self, voice_stealing: VoiceStealingPolicy) -> Self {
+        self.voice_stealing = voice_stealing;
+        self
+    }
+
+    /// Sets the linear gain everything is mixed at
+    pub fn with_master_volume(mut self, master_volume: f32) -> Self {
+        self.master_volume = master_volume;
+        self
+    }
+
+    /// Tunes everything so A4 plays at `master_tuning` Hz instead of 440 Hz
+    ///
+    /// # Panics
+    ///
+    /// If `master_tuning` isn't a positive, finite frequency.
+    pub fn with_master_tuning(mut self, master_tuning: f32) -> Self {
+        self.set_master_tuning(master_tuning);
+        self
+    }
+
+    pub fn add_track(&mut self, mut midi_track: MidiAudioTrack) -> MidiAudioTrackHandle {
+        midi_track.set_samples_per_second(self.samples_per_second);
+        midi_track.master_tuning = frequency_to_cents(self.master_tuning);
         let handle = MidiAudioTrackHandle(self.tracks.len());
         self.tracks.insert(handle, midi_track);
         handle
@@ -50,7 +173,7 @@ impl MidiAudio {
 
     pub fn from_bytes(soundfont_bytes: &[u8]) -> Self {
         let soundfont = Arc::new(SoundFont::new(&mut Cursor::new(soundfont_bytes)).unwrap());
-        Self::new(soundfont)
+        Self::from_soundfont_bank(SoundFontBank::new(soundfont).with_modulators(soundfont_bytes))
     }
 
     pub fn tick(&mut self, delta: Duration) {
@@ -120,28 +243,38 @@ impl MidiAudio {
             for track in self.tracks.values_mut().filter(|track| track.is_playing) {
                 track.tick_midi(&self.soundfont);
             }
+            self.steal_voices();
+
+            let wave_data = self.soundfont.wave_data();
+            for track in self.tracks.values_mut() {
+                let interpolation = track.interpolation.unwrap_or(self.interpolation);
+                track
+                    .channels
+                    .values_mut()
+                    .for_each(|channel| channel.render(wave_data, interpolation));
+            }
         }
 
         let sample = self
             .tracks
-            .values_mut()
-            .flat_map(|track| track.channels.values())
-            .flat_map(|channel| channel.voices.values())
-            .map(|voice| {
-                voice.sample(
-                    self.soundfont.soundfont.get_wave_data(),
-                    self.current_audio_channel,
-                )
+            .values()
+            .map(|track| {
+                track
+                    .channels
+                    .values()
+                    .flat_map(|channel| channel.all_voices())
+                    .map(|voice| voice.sample(self.current_audio_channel, self.num_audio_channels))
+                    .sum::<f32>()
+                    * track.gain
             })
-            .sum::<i32>()
-            .clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            .sum::<f32>();
+        let sample = soft_clip(sample * self.master_volume);
 
         if self.current_audio_channel == 0 {
             self.tracks
                 .values_mut()
                 .flat_map(|track| track.channels.values_mut())
-                .flat_map(|channel| channel.voices.values_mut())
-                .for_each(Voice::tick);
+                .for_each(|channel| channel.tick(1.0 / self.samples_per_second as f32));
         }
         self.current_audio_channel = (self.current_audio_channel + 1) % self.num_audio_channels;
 
@@ -166,9 +299,54 @@ impl MidiAudio {
                 },
                 &self.soundfont,
             );
+        self.steal_voices();
         Ok(())
     }
 
+    /// Fades out voices past the per-track and global voice limits, once new voices have started
+    fn steal_voices(&mut self) {
+        let mut newest: Option<(MidiAudioTrackHandle, NewestVoice)> = None;
+        for (&handle, track) in &mut self.tracks {
+            let Some(newest_voice) = track.newest_voice.take() else {
+                continue;
+            };
+            if let Some(max_voices) = track.max_voices {
+                let active_voices = track.active_voices();
+                if active_voices > max_voices {
+                    let voice_stealing = track.voice_stealing.unwrap_or(self.voice_stealing);
+                    let mut candidates = vec![];
+                    track.steal_candidates(handle, (handle, newest_voice), &mut candidates);
+                    track.steal(&voice_stealing.choose(candidates, active_voices - max_voices));
+                }
+            }
+            if newest.is_none_or(|(_, newest)| newest_voice.id > newest.id) {
+                newest = Some((handle, newest_voice));
+            }
+        }
+
+        let (Some(max_voices), Some(newest)) = (self.max_voices, newest) else {
+            return;
+        };
+        let active_voices = self
+            .tracks
+            .values()
+            .map(MidiAudioTrack::active_voices)
+            .sum::<usize>();
+        if active_voices <= max_voices {
+            return;
+        }
+        let mut candidates = vec![];
+        for (&handle, track) in &self.tracks {
+            track.steal_candidates(handle, newest, &mut candidates);
+        }
+        let stolen = self
+            .voice_stealing
+            .choose(candidates, active_voices - max_voices);
+        for track in self.tracks.values_mut() {
+            track.steal(&stolen);
+        }
+    }
+
     pub fn stop_playing_note(&mut self, note: Note) -> Result<(), NoTracksError> {
         self.tracks
             .get_mut(&MidiAudioTrackHandle(0))
@@ -183,6 +361,151 @@ impl MidiAudio {
         Ok(())
     }
 
+    /// Sets the linear gain the track is mixed at
+    pub fn set_track_gain(&mut self, handle: MidiAudioTrackHandle, gain: f32) {
+        if let Some(track) = self.tracks.get_mut(&handle) {
+            track.gain = gain;
+        }
+    }
+
+    /// Sets the linear gain everything is mixed at
+    pub fn set_master_volume(&mut self, master_volume: f32) {
+        self.master_volume = master_volume;
+    }
+
+    /// Tunes everything so A4 plays at `master_tuning` Hz instead of 440 Hz, starting from the next notes
+    ///
+    /// # Panics
+    ///
+    /// If `master_tuning` isn't a positive, finite frequency.
+    pub fn set_master_tuning(&mut self, master_tuning: f32) {
+        assert!(
+            master_tuning.is_finite() && master_tuning > 0.0,
+            "The master tuning must be a positive frequency"
+        );
+        self.master_tuning = master_tuning;
+        for track in self.tracks.values_mut() {
+            track.master_tuning = frequency_to_cents(master_tuning);
+        }
+    }
+
+    /// Sets the pitch of every key on a channel, starting from the next notes.
+    /// MIDI Tuning Standard messages in the track change it afterwards, and scale/octave tunings or tuning program selects replace it.
+    pub fn set_channel_tuning(
+        &mut self,
+        handle: MidiAudioTrackHandle,
+        channel: u8,
+        tuning_table: TuningTable,
+    ) {
+        if let Some(channel) = self
+            .tracks
+            .get_mut(&handle)
+            .and_then(|track| track.channels.get_mut(&channel))
+        {
+            channel.tuning_table = tuning_table;
+        }
+    }
+
+    /// Sets a channel's volume like CC7 would, from 0 to 127
+    pub fn set_channel_volume(&mut self, handle: MidiAudioTrackHandle, channel: u8, volume: u8) {
+        self.control_change(handle, channel, Controller::Volume, volume);
+    }
+
+    /// Sets a channel's expression like CC11 would, from 0 to 127
+    pub fn set_channel_expression(
+        &mut self,
+        handle: MidiAudioTrackHandle,
+        channel: u8,
+        expression: u8,
+    ) {
+        self.control_change(handle, channel, Controller::Expression, expression);
+    }
+
+    /// Sets a channel's aftertouch, from 0 to 127
+    pub fn set_channel_pressure(
+        &mut self,
+        handle: MidiAudioTrackHandle,
+        channel: u8,
+        pressure: u8,
+    ) {
+        self.interpret_event(handle, MidiEvent::ChannelPressure { channel, pressure });
+    }
+
+    /// Sets the aftertouch of a single note on a channel, from 0 to 127
+    pub fn set_poly_pressure(
+        &mut self,
+        handle: MidiAudioTrackHandle,
+        channel: u8,
+        note: Note,
+        pressure: u8,
+    ) {
+        self.interpret_event(
+            handle,
+            MidiEvent::PolyPressure {
+                channel,
+                note: note.position(),
+                pressure,
+            },
+        );
+    }
+
+    fn control_change(
+        &mut self,
+        handle: MidiAudioTrackHandle,
+        channel: u8,
+        controller: Controller,
+        value: u8,
+    ) {
+        self.interpret_event(
+            handle,
+            MidiEvent::ControlChange {
+                channel,
+                controller: controller as u8,
+                value,
+            },
+        );
+    }
+
+    /// Releases every note on a channel, or every channel of the track if `channel` is `None`, like CC123 would.
+    /// Notes held by the sustain or sostenuto pedal keep ringing until the pedal lifts.
+    pub fn all_notes_off(&mut self, handle: MidiAudioTrackHandle, channel: Option<u8>) {
+        self.channel_mode(handle, channel, Controller::AllNotesOff);
+    }
+
+    /// Cuts off every voice on a channel, or every channel of the track if `channel` is `None`, like CC120 would.
+    /// Unlike [`MidiAudio::all_notes_off`] this skips release times and pedals, making it a panic button.
+    pub fn all_sound_off(&mut self, handle: MidiAudioTrackHandle, channel: Option<u8>) {
+        self.channel_mode(handle, channel, Controller::AllSoundOff);
+    }
+
+    /// Resets pitch bend, aftertouch, the mod wheel, expression and the pedals on a channel,
+    /// or every channel of the track if `channel` is `None`, like CC121 would
+    pub fn reset_all_controllers(&mut self, handle: MidiAudioTrackHandle, channel: Option<u8>) {
+        self.channel_mode(handle, channel, Controller::ResetAllControllers);
+    }
+
+    fn channel_mode(
+        &mut self,
+        handle: MidiAudioTrackHandle,
+        channel: Option<u8>,
+        controller: Controller,
+    ) {
+        let channels = match (channel, self.tracks.get(&handle)) {
+            (Some(channel), _) => vec![channel],
+            (None, Some(track)) => track.channels.keys().copied().collect(),
+            (None, None) => return,
+        };
+        for channel in channels {
+            self.control_change(handle, channel, controller, 0);
+        }
+    }
+
+    fn interpret_event(&mut self, handle: MidiAudioTrackHandle, event: MidiEvent) {
+        if let Some(track) = self.tracks.get_mut(&handle) {
+            track.interpret_event(event, &self.soundfont);
+        }
+    }
+
     pub fn is_playing(&self, handle: &MidiAudioTrackHandle) -> bool {
         self.tracks
             .get(handle)
@@ -201,9 +524,27 @@ impl MidiAudio {
 #[derive(Debug, Default, Clone, Copy)]
 pub struct NoTracksError;
 
+/// The channel layout of the audio output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputChannels {
+    /// Folds the stereo mix down to one channel
+    Mono,
+    #[default]
+    Stereo,
+}
+
+impl OutputChannels {
+    fn count(self) -> u16 {
+        match self {
+            OutputChannels::Mono => 1,
+            OutputChannels::Stereo => 2,
+        }
+    }
+}
+
 pub struct MidiAudioTrack {
     midi_track: MidiTrack,
-    /// Track => Channel => Note => Voice
+    /// Track => Channel => Voices
     channels: HashMap<u8, Channel>,
     ticks_per_sample: f64,
     samples_per_second: f64,
@@ -214,11 +555,27 @@ pub struct MidiAudioTrack {
     beats_per_bar: f64,
     queue: Vec<MidiQueueEvent>,
     is_playing: bool,
+    /// Linear gain the track is mixed at
+    gain: f32,
+    /// Overrides the interpolation of the `MidiAudio`
+    interpolation: Option<InterpolationMode>,
+    max_voices: Option<usize>,
+    /// Overrides the voice stealing policy of the `MidiAudio`
+    voice_stealing: Option<VoiceStealingPolicy>,
+    retrigger: RetriggerPolicy,
+    /// Applied to every voice on the track
+    modulators: Vec<Modulator>,
+    /// MIDI Tuning Standard tuning programs by bank and program, where the ones never changed are equal temperament
+    tuning_programs: HashMap<(u8, u8), TuningTable>,
+    /// In cents, set by the `MidiAudio`
+    master_tuning: f32,
+    /// Set when a voice starts, until the `MidiAudio` checks the voice limits
+    newest_voice: Option<NewestVoice>,
 }
 
 impl MidiAudioTrack {
     pub fn new(midi_track: MidiTrack, time_signature: f64) -> Self {
-        let samples_per_second = 44100.0;
+        let samples_per_second = DEFAULT_SAMPLES_PER_SECOND;
         let beats_per_second = 120.0 / 60.0;
         let ticks_per_beat = midi_track.ticks_per_beat as f64;
         let ticks_per_sample = (ticks_per_beat * beats_per_second) / samples_per_second;
@@ -226,16 +583,7 @@ impl MidiAudioTrack {
         let beats_per_bar = time_signature * 4.0;
 
         let channels = (0..16)
-            .map(|i| {
-                (
-                    i,
-                    Channel {
-                        bank_number: if i == 9 { 128 } else { 0 },
-                        patch_number: 0,
-                        voices: HashMap::new(),
-                    },
-                )
-            })
+            .map(|i| (i, Channel::new(if i == 9 { PERCUSSION_BANK } else { 0 }, 0)))
             .collect();
 
         Self {
@@ -250,6 +598,15 @@ impl MidiAudioTrack {
             beats_per_bar,
             queue: vec![],
             is_playing: true,
+            gain: 1.0,
+            interpolation: None,
+            max_voices: None,
+            voice_stealing: None,
+            retrigger: RetriggerPolicy::default(),
+            modulators: default_modulators(),
+            tuning_programs: HashMap::new(),
+            master_tuning: 0.0,
+            newest_voice: None,
         }
     }
 
@@ -257,20 +614,20 @@ impl MidiAudioTrack {
         Self::new(MidiTrack::from_bytes(track_bytes), time_signature)
     }
 
+    /// Sets a channel's instrument, ignoring any bank selects and program changes for it in the MIDI file
     pub fn with_channel_patch(
         mut self,
         channel_number: u8,
         bank_number: u8,
         patch_number: u8,
     ) -> Self {
-        self.channels.insert(
-            channel_number,
-            Channel {
-                bank_number,
-                patch_number,
-                voices: HashMap::new(),
-            },
-        );
+        let channel = self
+            .channels
+            .entry(channel_number)
+            .or_insert_with(|| Channel::new(bank_number, patch_number));
+        channel.bank_number = bank_number;
+        channel.patch_number = patch_number;
+        channel.patch_locked = true;
         self
     }
 
@@ -279,6 +636,71 @@ impl MidiAudioTrack {
         self
     }
 
+    /// Sets the linear gain the track is mixed at
+    pub fn with_gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    /// Overrides how this track reads between wave samples
+    pub fn with_interpolation(mut self, interpolation: InterpolationMode) -> Self {
+        self.interpolation = Some(interpolation);
+        self
+    }
+
+    /// Caps how many voices this track can play at once, fading out voices picked by the stealing policy past that
+    pub fn with_max_voices(mut self, max_voices: usize) -> Self {
+        self.max_voices = Some(max_voices);
+        self
+    }
+
+    /// Overrides which of this track's voices get stolen once its voice limit is hit
+    pub fn with_voice_stealing(mut self, voice_stealing: VoiceStealingPolicy) -> Self {
+        self.voice_stealing = Some(voice_stealing);
+        self
+    }
+
+    /// Sets what a note on does to a key that's already held down
+    pub fn with_retrigger(mut self, retrigger: RetriggerPolicy) -> Self {
+        self.retrigger = retrigger;
+        self
+    }
+
+    /// Adds a modulator to every voice on the track, replacing any with the same sources and destination.
+    /// Modulators from the SoundFont's instrument zones in turn replace identical track modulators.
+    pub fn with_modulator(mut self, modulator: Modulator) -> Self {
+        override_modulators(&mut self.modulators, &[modulator]);
+        self
+    }
+
+    /// Sets the pitch of every key on a channel, for tunings other than 12-tone equal temperament.
+    /// MIDI Tuning Standard messages in the track change it afterwards, and scale/octave tunings or tuning program selects replace it.
+    pub fn with_channel_tuning(mut self, channel_number: u8, tuning_table: TuningTable) -> Self {
+        if let Some(channel) = self.channels.get_mut(&channel_number) {
+            channel.tuning_table = tuning_table;
+        }
+        self
+    }
+
+    /// Sets how long a portamento glide takes on a channel, in seconds, until CC5 changes it.
+    /// Channels start at 0.1 seconds. CC5 maps its value `v` to `(v / 127)² × 4` seconds,
+    /// so 0 jumps straight to the new note, 64 takes about a second and 127 takes 4 seconds.
+    pub fn with_portamento_time(mut self, channel_number: u8, portamento_time: f32) -> Self {
+        if let Some(channel) = self.channels.get_mut(&channel_number) {
+            channel.portamento_time = portamento_time;
+        }
+        self
+    }
+
+    /// Sets the priority used by [`VoiceStealingPolicy::LowestPriority`], where voices on lower priority channels get stolen first.
+    /// Channels default to 0.
+    pub fn with_channel_priority(mut self, channel_number: u8, priority: u8) -> Self {
+        if let Some(channel) = self.channels.get_mut(&channel_number) {
+            channel.priority = priority;
+        }
+        self
+    }
+
     pub fn stopped(mut self) -> Self {
         self.is_playing = false;
         self
@@ -325,31 +747,300 @@ impl MidiAudioTrack {
 
     pub fn interpret_event(&mut self, event: MidiEvent, soundfont: &SoundFontBank) {
         match event {
+            // A note on with no velocity is a note off by convention
             MidiEvent::NoteOn {
                 channel,
                 note,
+                velocity: 0,
+            } => self.interpret_event(MidiEvent::NoteOff { channel, note }, soundfont),
+            MidiEvent::NoteOn {
+                channel: channel_number,
+                note,
                 velocity,
             } => {
-                if let Some(voice) = self.create_voice(channel, note, velocity, soundfont)
-                    && let Some(channel) = self.channels.get_mut(&channel)
+                // In mono mode a held voice moves over to the new note instead of a new voice starting
+                if let Some(channel) = self.channels.get_mut(&channel_number)
+                    && channel.mono
+                {
+                    channel
+                        .held_notes
+                        .retain(|&(held_note, _)| held_note != note);
+                    channel.held_notes.push((note, velocity));
+                    if self.legato(channel_number, note, velocity, soundfont) {
+                        return;
+                    }
+                    if let Some(channel) = self.channels.get_mut(&channel_number) {
+                        channel.release_held_voices();
+                    }
+                }
+
+                let retrigger = self.retrigger;
+                if retrigger == RetriggerPolicy::Ignore
+                    && self
+                        .channels
+                        .get(&channel_number)
+                        .is_some_and(|channel| channel.is_held(note))
+                {
+                    return;
+                }
+                if let Some(mut voice) =
+                    self.create_voice(channel_number, note, velocity, soundfont)
+                    && let Some(channel) = self.channels.get_mut(&channel_number)
                 {
-                    channel.voices.insert(note, voice);
+                    channel.choke(&voice.exclusive_classes());
+                    if retrigger == RetriggerPolicy::Retrigger {
+                        voice.pending_note_offs = channel.retrigger(note);
+                    }
+                    if channel.portamento
+                        && let Some(last_note) = channel.last_note
+                    {
+                        voice.glide(
+                            channel.key_pitch(last_note) - channel.key_pitch(note),
+                            channel.portamento_time,
+                        );
+                    }
+                    channel.last_note = Some(note);
+                    self.newest_voice = Some(NewestVoice {
+                        channel: channel_number,
+                        note,
+                        id: voice.id,
+                    });
+                    channel.active_voices += 1;
+                    channel.voices.push(voice);
                 }
             }
-            MidiEvent::NoteOff { channel, note } => {
-                if let Some(channel) = self.channels.get_mut(&channel) {
-                    channel.voices.remove(&note);
+            MidiEvent::NoteOff {
+                channel: channel_number,
+                note,
+            } => {
+                if let Some(channel) = self.channels.get_mut(&channel_number) {
+                    if channel.mono {
+                        channel
+                            .held_notes
+                            .retain(|&(held_note, _)| held_note != note);
+                        // Letting go of the sounding note goes back to the last key still held down
+                        if channel
+                            .voices
+                            .last()
+                            .is_some_and(|voice| voice.note == note)
+                            && let Some(&(held_note, velocity)) = channel.held_notes.last()
+                        {
+                            self.interpret_event(
+                                MidiEvent::NoteOn {
+                                    channel: channel_number,
+                                    note: held_note,
+                                    velocity,
+                                },
+                                soundfont,
+                            );
+                            return;
+                        }
+                    }
+                    channel.release_voice(note);
                 }
             }
             MidiEvent::SetTempo {
                 tempo: beats_per_minute,
             } => {
                 self.beats_per_second = beats_per_minute / 60.0;
-                self.ticks_per_sample = (self.midi_track.ticks_per_beat as f64
-                    * self.beats_per_second)
-                    / self.samples_per_second;
+                self.update_ticks_per_sample();
+            }
+            MidiEvent::ControlChange {
+                channel,
+                controller,
+                value,
+            } => {
+                if let Some(channel) = self.channels.get_mut(&channel) {
+                    let selects_tuning_program = controller == Controller::DataEntry as u8
+                        && channel.registered_parameter
+                            == Some(RegisteredParameter::TuningProgram as u16);
+                    channel.control_change(controller, value);
+                    if selects_tuning_program {
+                        channel.tuning_table = self
+                            .tuning_programs
+                            .get(&channel.tuning_program)
+                            .cloned()
+                            .unwrap_or_default();
+                    }
+                    match Controller::try_from(controller) {
+                        Ok(Controller::ResetAllControllers) => channel.update_modulation(|_| true),
+                        // Can change the pitch bend range
+                        Ok(Controller::DataEntry | Controller::DataEntryFine) => channel
+                            .update_modulation(|source| {
+                                source == ModulatorSource::PitchWheelSensitivity
+                            }),
+                        _ => channel.update_modulation(|source| {
+                            source == ModulatorSource::Controller(controller)
+                        }),
+                    }
+                }
+            }
+            MidiEvent::BankSelect {
+                channel,
+                bank,
+                fine,
+            } => {
+                if let Some(channel) = self.channels.get_mut(&channel) {
+                    if fine {
+                        channel.bank_select_fine = bank;
+                    } else {
+                        channel.bank_select = bank;
+                    }
+                }
             }
+            MidiEvent::ProgramChange { channel, program } => {
+                if let Some(channel) = self.channels.get_mut(&channel) {
+                    channel.program_change(program);
+                }
+            }
+            MidiEvent::ChannelPressure { channel, pressure } => {
+                if let Some(channel) = self.channels.get_mut(&channel) {
+                    channel.channel_pressure = pressure;
+                    channel.update_modulation(|source| source == ModulatorSource::ChannelPressure);
+                }
+            }
+            MidiEvent::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => {
+                if let Some(channel) = self.channels.get_mut(&channel) {
+                    channel
+                        .voices
+                        .iter_mut()
+                        .filter(|voice| voice.note == note)
+                        .for_each(|voice| voice.poly_pressure = pressure);
+                    channel.update_modulation(|source| source == ModulatorSource::PolyPressure);
+                }
+            }
+            MidiEvent::SystemReset => {
+                for (&channel_number, channel) in &mut self.channels {
+                    channel.reset(channel_number == 9);
+                    channel.update_modulation(|_| true);
+                }
+            }
+            MidiEvent::DrumPart { channel, drums } => {
+                if let Some(channel) = self.channels.get_mut(&channel) {
+                    channel.set_drums(drums);
+                }
+            }
+            MidiEvent::Retune { channels, retuning } => {
+                for (_, channel) in self
+                    .channels
+                    .iter_mut()
+                    .filter(|&(&channel_number, _)| channels & (1 << (channel_number & 0x0f)) != 0)
+                {
+                    channel.tuning_table.retune(&retuning);
+                }
+            }
+            MidiEvent::RetuneProgram {
+                bank,
+                program,
+                retuning,
+            } => {
+                self.tuning_programs
+                    .entry((bank, program))
+                    .or_default()
+                    .retune(&retuning);
+                // Changed on top of the channel's table, so a table set in code keeps the keys the message leaves alone
+                for channel in self
+                    .channels
+                    .values_mut()
+                    .filter(|channel| channel.tuning_program == (bank, program))
+                {
+                    channel.tuning_table.retune(&retuning);
+                }
+            }
+            MidiEvent::PitchBend { channel, value } => {
+                if let Some(channel) = self.channels.get_mut(&channel) {
+                    channel.pitch_wheel = value;
+                    channel.update_modulation(|source| source == ModulatorSource::PitchWheel);
+                }
+            }
+        }
+    }
+
+    /// How many voices on the track count towards voice limits
+    fn active_voices(&self) -> usize {
+        self.channels
+            .values()
+            .map(|channel| channel.active_voices)
+            .sum()
+    }
+
+    /// Adds the track's voices that can be stolen to `candidates`, leaving out the newest voice
+    fn steal_candidates(
+        &self,
+        handle: MidiAudioTrackHandle,
+        (newest_track, newest): (MidiAudioTrackHandle, NewestVoice),
+        candidates: &mut Vec<StealCandidate>,
+    ) {
+        for (&channel_number, channel) in &self.channels {
+            let newest_channel = handle == newest_track && channel_number == newest.channel;
+            candidates.extend(
+                channel
+                    .all_voices()
+                    .filter(|voice| voice.is_active() && voice.id != newest.id)
+                    .map(|voice| StealCandidate {
+                        id: voice.id,
+                        released: voice.is_released(),
+                        just_started: voice.age == 0.0,
+                        level: voice.level(),
+                        channel_priority: channel.priority,
+                        same_note: newest_channel && voice.note == newest.note,
+                    }),
+            );
+        }
+    }
+
+    /// Moves the newest voice on a mono channel over to `note` without restarting it, returning whether it could.
+    /// Like FluidSynth, only a voice still sounding whose zones are exactly the ones the new note plays gets moved.
+    fn legato(
+        &mut self,
+        channel_number: u8,
+        note: u8,
+        velocity: u8,
+        soundfont: &SoundFontBank,
+    ) -> bool {
+        let Some(channel) = self.channels.get_mut(&channel_number) else {
+            return false;
+        };
+        let Some(voice) = channel.voices.last().filter(|voice| voice.can_legato()) else {
+            return false;
+        };
+        let same_regions = soundfont
+            .get_regions(
+                note as i32,
+                velocity as i32,
+                channel.bank_number,
+                channel.patch_number,
+            )
+            .is_some_and(|regions| {
+                regions
+                    .iter()
+                    .map(|region| region.id)
+                    .eq(voice.samples.iter().map(|sample| sample.region))
+            });
+        if same_regions {
+            channel.legato(note);
         }
+        same_regions
+    }
+
+    fn steal(&mut self, ids: &HashSet<u64>) {
+        self.channels
+            .values_mut()
+            .for_each(|channel| channel.steal(ids));
+    }
+
+    fn set_samples_per_second(&mut self, samples_per_second: f64) {
+        self.samples_per_second = samples_per_second;
+        self.update_ticks_per_sample();
+    }
+
+    fn update_ticks_per_sample(&mut self) {
+        self.ticks_per_sample = (self.midi_track.ticks_per_beat as f64 * self.beats_per_second)
+            / self.samples_per_second;
     }
 
     fn create_voice(
@@ -361,54 +1052,125 @@ impl MidiAudioTrack {
     ) -> Option<Voice> {
         let note = note as i32;
         let velocity = velocity as i32;
-        let volume = velocity as f32 / 127.0;
-
         let channel = &self.channels[&channel_index];
-        let sample_headers = soundfont.get_sample_headers(
-            note,
-            velocity,
-            channel.bank_number,
-            channel.patch_number,
-        )?;
-        let samples = sample_headers
+        // Velocity is handled by the modulators
+        let (volume, cutoff) = if channel.soft {
+            (
+                decibels_to_gain(SOFT_PEDAL_ATTENUATION),
+                cents_to_ratio(SOFT_PEDAL_CUTOFF),
+            )
+        } else {
+            (1.0, 1.0)
+        };
+
+        let regions =
+            soundfont.get_regions(note, velocity, channel.bank_number, channel.patch_number)?;
+        let modulator_state = channel.modulator_state(velocity as u8, note as u8, 0);
+        let samples = regions
             .into_iter()
-            .map(|sample| VoiceSample {
-                speed: 2_f32.powf(
-                    (note as f32 - sample.get_original_pitch() as f32
-                        + sample.get_pitch_correction() as f32 / 100.0)
-                        / 12.0,
-                ),
-                current_sample: sample.get_start() as f64,
-                end_sample: sample.get_end() as f64,
-                sample_type: sample.get_sample_type().try_into().unwrap(),
-                volume,
+            .map(|region| {
+                // Zones with a fixed key or velocity play every note as that key or velocity
+                let key = region.key(note);
+                let filter_cutoff = region.initial_filter_cutoff() * cutoff;
+                // Samples recorded at a different rate than the output need to be stepped through faster or slower
+                // Drum kits turn off scale tuning so every key plays its sample as is, which should keep them out of the tuning table too
+                let table_tuning =
+                    channel.tuning_table.offset(key as u8) * region.scale_tuning() as f32 / 100.0;
+                let speed = cents_to_ratio(
+                    region.pitch_offset(key) + table_tuning + channel.tuning() + self.master_tuning,
+                ) * (region.sample.get_sample_rate() as f64 / self.samples_per_second)
+                    as f32;
+                let mut sample = VoiceSample {
+                    speed,
+                    current_speed: speed,
+                    current_sample: region.sample_start() as f64,
+                    start_sample: region.sample_start() as f64,
+                    end_sample: region.sample_end() as f64,
+                    start_loop: region.sample_start_loop() as f64,
+                    end_loop: region.sample_end_loop() as f64,
+                    loop_mode: region.loop_mode(),
+                    // Stereo pairs are panned apart by the pan generators of their zones, like other SF2 players do
+                    pan: region.pan(),
+                    pan_gains: pan_gains(0.0),
+                    modulation: ModulatorCache::new(
+                        region.modulators(&self.modulators),
+                        &ModulatorState {
+                            velocity: region.velocity(velocity) as u8,
+                            key: key as u8,
+                            ..modulator_state
+                        },
+                    ),
+                    modulation_gain: 1.0,
+                    key_scaling: region.scale_tuning() as f32 / 100.0,
+                    glide: 0.0,
+                    glide_rate: 0.0,
+                    volume: volume * decibels_to_gain(region.initial_attenuation()),
+                    volume_envelope: VolumeEnvelope::new(&region, key),
+                    filter_cutoff,
+                    filter: LowPassFilter::new(
+                        self.samples_per_second as f32,
+                        filter_cutoff,
+                        region.initial_filter_q(),
+                    ),
+                    modulators: VoiceModulators::new(&region, key),
+                    exclusive_class: region.exclusive_class(),
+                    output: 0.0,
+                    released: false,
+                    stolen: false,
+                    region: region.id,
+                    fixed_key: region.fixed_key,
+                    fixed_velocity: region.fixed_velocity,
+                };
+                sample.apply_modulation();
+                sample
             })
             .collect::<Vec<_>>();
         if samples.is_empty() {
             return None;
         }
-        Some(Voice { samples })
+        Some(Voice {
+            id: NEXT_VOICE_ID.fetch_add(1, atomic::Ordering::Relaxed),
+            note: note as u8,
+            age: 0.0,
+            stolen: false,
+            pending_note_offs: 0,
+            note_off: false,
+            sostenuto: false,
+            velocity: velocity as u8,
+            poly_pressure: 0,
+            samples,
+        })
     }
 }
 
+/// The newest voice on a track, which the stealing policies spare
+#[derive(Debug, Clone, Copy)]
+struct NewestVoice {
+    channel: u8,
+    note: u8,
+    id: u64,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect)]
 pub struct MidiAudioTrackHandle(usize);
 
-pub struct MidiDecoder {
-    buffer: Arc<Mutex<VecDeque<i16>>>,
+pub struct MidiDecoder<S: MidiSample = i16> {
+    buffer: Arc<Mutex<VecDeque<f32>>>,
     num_audio_channels: u16,
     samples_per_second: u32,
+    sample: PhantomData<S>,
 }
 
-impl Iterator for MidiDecoder {
-    type Item = i16;
+impl<S: MidiSample> Iterator for MidiDecoder<S> {
+    type Item = S;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.buffer.lock().unwrap().pop_front().or(Some(0))
+        let sample = self.buffer.lock().unwrap().pop_front().unwrap_or(0.0);
+        Some(S::from_f32(sample))
     }
 }
 
-impl Source for MidiDecoder {
+impl<S: MidiSample> Source for MidiDecoder<S> {
     fn current_frame_len(&self) -> Option<usize> {
         if self.buffer.lock().unwrap().is_empty() {
             Some(1)
@@ -430,145 +1192,900 @@ impl Source for MidiDecoder {
     }
 }
 
-impl Decodable for MidiAudio {
-    type DecoderItem = <MidiDecoder as Iterator>::Item;
+impl<S: MidiSample> Decodable for MidiAudio<S> {
+    type DecoderItem = S;
 
-    type Decoder = MidiDecoder;
+    type Decoder = MidiDecoder<S>;
 
     fn decoder(&self) -> Self::Decoder {
         MidiDecoder {
             buffer: self.buffer.clone(),
             num_audio_channels: self.num_audio_channels,
             samples_per_second: self.samples_per_second as u32,
+            sample: PhantomData,
         }
     }
 }
 
+static NEXT_VOICE_ID: AtomicU64 = AtomicU64::new(0);
+
 struct Voice {
+    /// Increases with every voice started
+    id: u64,
+    note: u8,
+    /// In seconds
+    age: f32,
+    /// Whether the voice is being faded out to make room for others
+    stolen: bool,
+    /// Note offs to swallow before releasing, for the voices this one retriggered
+    pending_note_offs: u32,
+    /// Whether the note off came in while a pedal was holding the voice, so it releases once the pedals let go
+    note_off: bool,
+    /// Whether the voice was held down when the sostenuto pedal was pressed
+    sostenuto: bool,
+    velocity: u8,
+    /// Aftertouch on the voice's note
+    poly_pressure: u8,
     samples: Vec<VoiceSample>,
 }
 
 impl Voice {
-    fn tick(&mut self) {
-        self.samples.iter_mut().for_each(VoiceSample::tick);
+    fn tick(&mut self, delta: f32) {
+        self.age += delta;
+        self.samples
+            .iter_mut()
+            .for_each(|sample| sample.tick(delta));
+    }
+
+    fn release(&mut self) {
+        self.samples.iter_mut().for_each(VoiceSample::release);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.samples.iter().all(VoiceSample::is_finished)
     }
 
-    fn sample(&self, wave_data: &[i16], current_audio_channel: u16) -> i32 {
+    /// Slides the pitch from `cents` away back to the voice's own pitch over `time` seconds
+    fn glide(&mut self, cents: f32, time: f32) {
+        self.samples
+            .iter_mut()
+            .for_each(|sample| sample.glide(cents, time));
+    }
+
+    /// Moves the voice `cents` over to another note without restarting it, gliding there if `glide_time` is set
+    fn legato(&mut self, note: u8, cents: f32, glide_time: Option<f32>) {
+        self.note = note;
+        self.note_off = false;
+        self.pending_note_offs = 0;
+        // Samples with a fixed key keep their pitch
+        for sample in self
+            .samples
+            .iter_mut()
+            .filter(|sample| sample.fixed_key.is_none())
+        {
+            sample.speed *= cents_to_ratio(cents * sample.key_scaling);
+            match glide_time {
+                Some(time) => sample.glide(-cents, time),
+                None => sample.glide = 0.0,
+            }
+        }
+    }
+
+    /// Whether the voice is still sounding for a new note to take over, rather than stolen, finished or choked
+    fn can_legato(&self) -> bool {
+        !self.stolen && !self.is_finished() && !self.samples.iter().all(|sample| sample.stolen)
+    }
+
+    /// Quickly fades the voice out
+    fn steal(&mut self) {
+        self.stolen = true;
+        self.samples.iter_mut().for_each(VoiceSample::steal);
+    }
+
+    fn exclusive_classes(&self) -> Vec<i32> {
         self.samples
             .iter()
-            .filter(|sample| sample.current_sample < sample.end_sample) // Remove this once loops are implemented
-            .filter(|sample| {
-                sample.sample_type == SampleType::Mono || {
-                    if current_audio_channel == 0 {
-                        sample.sample_type == SampleType::Left
-                    } else {
-                        sample.sample_type == SampleType::Right
-                    }
-                }
-            })
+            .map(|sample| sample.exclusive_class)
+            .filter(|&exclusive_class| exclusive_class != 0)
+            .collect()
+    }
+
+    /// Quickly fades out the samples in any of the exclusive classes
+    fn choke(&mut self, exclusive_classes: &[i32]) {
+        self.samples
+            .iter_mut()
+            .filter(|sample| exclusive_classes.contains(&sample.exclusive_class))
+            .for_each(VoiceSample::steal);
+    }
+
+    /// Whether the voice counts towards voice limits
+    fn is_active(&self) -> bool {
+        !self.stolen && !self.is_finished()
+    }
+
+    fn is_released(&self) -> bool {
+        self.samples.iter().all(|sample| sample.released)
+    }
+
+    /// The loudest gain any of the voice's samples currently play at
+    fn level(&self) -> f32 {
+        self.samples
+            .iter()
+            .map(|sample| sample.volume * sample.volume_envelope.gain() * sample.modulation_gain)
+            .fold(0.0, f32::max)
+    }
+
+    fn render(&mut self, wave_data: &[i16], interpolation: InterpolationMode) {
+        self.samples
+            .iter_mut()
+            .for_each(|sample| sample.render(wave_data, interpolation));
+    }
+
+    /// Evaluates the modulators reading a source that `changed` matches again
+    fn update_modulation(
+        &mut self,
+        state: &ModulatorState,
+        changed: &impl Fn(ModulatorSource) -> bool,
+    ) {
+        for sample in &mut self.samples {
+            let state = ModulatorState {
+                velocity: sample.fixed_velocity.unwrap_or(state.velocity),
+                key: sample.fixed_key.unwrap_or(state.key),
+                ..*state
+            };
+            if sample.modulation.update(&state, changed) {
+                sample.apply_modulation();
+            }
+        }
+    }
+
+    fn sample(&self, current_audio_channel: u16, num_audio_channels: u16) -> f32 {
+        self.samples
+            .iter()
+            .filter(|sample| !sample.is_finished())
             .map(|sample| {
-                // This seems like such a hassle... Do we really need to interpolate?
-                let current_sample = sample.current_sample;
-                let floor = wave_data[current_sample.floor() as usize] as f32;
-                let ceil = wave_data[current_sample.ceil() as usize] as f32;
-                let fraction = current_sample.fract() as f32;
-                ((ceil * fraction + floor * (1.0 - fraction)) * sample.volume) as i32
+                let (left, right) = sample.pan_gains;
+                let pan_gain = match (num_audio_channels, current_audio_channel) {
+                    // Folds back to a gain of 1 for centered samples
+                    (1, _) => (left + right) / SQRT_2,
+                    (_, 0) => left,
+                    _ => right,
+                };
+                sample.output
+                    * pan_gain
+                    * sample.volume
+                    * sample.volume_envelope.gain()
+                    * sample.modulators.gain()
+                    * sample.modulation_gain
             })
-            .sum::<i32>()
+            .sum::<f32>()
     }
 }
 
 struct VoiceSample {
+    /// Samples advanced per output sample, before modulation
     speed: f32,
+    /// Samples advanced per output sample, after modulation
+    current_speed: f32,
     current_sample: f64,
+    start_sample: f64,
     end_sample: f64,
-    sample_type: SampleType,
+    start_loop: f64,
+    end_loop: f64,
+    loop_mode: LoopMode,
+    /// From -50 (left) to 50 (right), before the channel's pan
+    pan: f32,
+    /// Left and right gains, including the pan modulators
+    pan_gains: (f32, f32),
+    modulation: ModulatorCache,
+    /// Linear gain from the attenuation modulators
+    modulation_gain: f32,
+    /// How far a semitone step in key moves the pitch in semitones, from the SF2 scale tuning
+    key_scaling: f32,
+    /// In cents, the portamento offset still left to slide away
+    glide: f32,
+    /// In cents per second
+    glide_rate: f32,
     volume: f32,
+    volume_envelope: VolumeEnvelope,
+    /// In Hz, before modulation
+    filter_cutoff: f32,
+    filter: LowPassFilter,
+    modulators: VoiceModulators,
+    /// Cuts off other samples on the channel with the same class when started, unless it's 0
+    exclusive_class: i32,
+    /// The filtered wave value at the current position from -1 to 1, before any gain
+    output: f32,
+    released: bool,
+    /// Whether the sample is being faded out quickly, because its voice was stolen or its exclusive class choked
+    stolen: bool,
+    /// The zones the sample plays, for mono mode to tell whether a new note can take over the voice
+    region: RegionId,
+    /// The key the sample's zone plays every note as, in place of the voice's note
+    fixed_key: Option<u8>,
+    /// The velocity the sample's zone plays every note with, in place of the voice's velocity
+    fixed_velocity: Option<u8>,
 }
 
 impl VoiceSample {
-    fn tick(&mut self) {
-        self.current_sample += self.speed as f64;
+    fn apply_modulation(&mut self) {
+        let modulation = self.modulation.outputs();
+        // SF2 doesn't let modulators make a voice louder than its unattenuated level
+        self.modulation_gain = decibels_to_gain(modulation.attenuation.max(0.0));
+        self.pan_gains = pan_gains(self.pan + modulation.pan);
+    }
+
+    fn render(&mut self, wave_data: &[i16], interpolation: InterpolationMode) {
+        if self.is_finished() {
+            self.output = 0.0;
+            return;
+        }
+        let index = self.current_sample as i64;
+        let radius = interpolation.radius();
+        let end = if self.is_looping() {
+            self.end_loop
+        } else {
+            self.end_sample
+        };
+        // Skip the wrapping and clamping when every sample read is safely inside the wave
+        let value = if index - radius >= self.start_sample as i64 && index + radius < end as i64 {
+            interpolation.interpolate(self.current_sample, self.current_speed, |index| {
+                wave_data[index as usize] as f32 / 32768.0
+            })
+        } else {
+            interpolation.interpolate(self.current_sample, self.current_speed, |index| {
+                wave_data[self.wave_index(index)] as f32 / 32768.0
+            })
+        };
+        self.output = self.filter.process(value);
+    }
+
+    fn tick(&mut self, delta: f32) {
+        let modulation = self.modulation.outputs();
+        self.volume_envelope.tick(delta);
+        self.modulators.tick(delta);
+        self.filter.set_cutoff(
+            self.filter_cutoff
+                * cents_to_ratio(self.modulators.filter_cutoff() + modulation.filter_cutoff),
+        );
+        self.glide = if self.glide > 0.0 {
+            (self.glide - self.glide_rate * delta).max(0.0)
+        } else {
+            (self.glide + self.glide_rate * delta).min(0.0)
+        };
+        let pitch = self.modulators.pitch()
+            + self.modulators.vibrato_lfo.value() * modulation.vibrato_lfo_to_pitch
+            + modulation.pitch
+            + self.glide;
+        self.current_speed = self.speed * cents_to_ratio(pitch);
+        self.current_sample += self.current_speed as f64;
+        if self.is_looping() && self.current_sample >= self.end_loop {
+            let loop_length = self.end_loop - self.start_loop;
+            self.current_sample =
+                self.start_loop + (self.current_sample - self.end_loop) % loop_length;
+        }
+    }
+
+    fn release(&mut self) {
+        self.released = true;
+        self.volume_envelope.release();
+        self.modulators.modulation_envelope.release();
+    }
+
+    /// `cents` is the difference in key pitch, before the scale tuning
+    fn glide(&mut self, cents: f32, time: f32) {
+        if time <= 0.0 {
+            self.glide = 0.0;
+            return;
+        }
+        self.glide += cents * self.key_scaling;
+        self.glide_rate = self.glide.abs() / time;
+    }
+
+    fn steal(&mut self) {
+        self.released = true;
+        self.stolen = true;
+        self.volume_envelope.steal();
+        self.modulators.modulation_envelope.release();
+    }
+
+    fn is_looping(&self) -> bool {
+        match self.loop_mode {
+            LoopMode::NoLoop => false,
+            LoopMode::Continuous => true,
+            LoopMode::LoopUntilRelease => !self.released,
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        (!self.is_looping() && self.current_sample >= self.end_sample)
+            || self.volume_envelope.is_finished()
+    }
+
+    /// Maps an index around the read position into the wave data, wrapping around the loop while looping
+    fn wave_index(&self, index: i64) -> usize {
+        let index = index as f64;
+        let index = if self.is_looping() && index >= self.end_loop {
+            self.start_loop + (index - self.end_loop) % (self.end_loop - self.start_loop)
+        } else {
+            index.clamp(self.start_sample, self.end_sample - 1.0)
+        };
+        index as usize
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
-#[repr(i32)]
-enum SampleType {
-    Mono = 1,
-    Right = 2,
-    Left = 4,
-    // There's also a "linked" type but I'm unsure when this would be used, usually `link` is just the other stereo channel
+/// The LFOs and modulation envelope of a voice sample, with how much they affect it
+struct VoiceModulators {
+    vibrato_lfo: Lfo,
+    modulation_lfo: Lfo,
+    modulation_envelope: ModulationEnvelope,
+    /// In cents
+    vibrato_lfo_to_pitch: f32,
+    /// In cents
+    modulation_lfo_to_pitch: f32,
+    /// In cents
+    modulation_envelope_to_pitch: f32,
+    /// In cents
+    modulation_lfo_to_filter_cutoff: f32,
+    /// In cents
+    modulation_envelope_to_filter_cutoff: f32,
+    /// Attenuation in dB
+    modulation_lfo_to_volume: f32,
 }
 
-struct Channel {
-    bank_number: u8,
-    patch_number: u8,
-    voices: HashMap<u8, Voice>,
+impl VoiceModulators {
+    fn new(region: &SoundFontRegion, note: i32) -> Self {
+        Self {
+            vibrato_lfo: Lfo::new(region.delay_vibrato_lfo(), region.frequency_vibrato_lfo()),
+            modulation_lfo: Lfo::new(
+                region.delay_modulation_lfo(),
+                region.frequency_modulation_lfo(),
+            ),
+            modulation_envelope: ModulationEnvelope::new(region, note),
+            vibrato_lfo_to_pitch: region.vibrato_lfo_to_pitch() as f32,
+            modulation_lfo_to_pitch: region.modulation_lfo_to_pitch() as f32,
+            modulation_envelope_to_pitch: region.modulation_envelope_to_pitch() as f32,
+            modulation_lfo_to_filter_cutoff: region.modulation_lfo_to_filter_cutoff() as f32,
+            modulation_envelope_to_filter_cutoff: region.modulation_envelope_to_filter_cutoff()
+                as f32,
+            modulation_lfo_to_volume: region.modulation_lfo_to_volume(),
+        }
+    }
+
+    fn tick(&mut self, delta: f32) {
+        self.vibrato_lfo.tick(delta);
+        self.modulation_lfo.tick(delta);
+        self.modulation_envelope.tick(delta);
+    }
+
+    /// In cents
+    fn pitch(&self) -> f32 {
+        self.vibrato_lfo.value() * self.vibrato_lfo_to_pitch
+            + self.modulation_lfo.value() * self.modulation_lfo_to_pitch
+            + self.modulation_envelope.value() * self.modulation_envelope_to_pitch
+    }
+
+    /// In cents
+    fn filter_cutoff(&self) -> f32 {
+        self.modulation_lfo.value() * self.modulation_lfo_to_filter_cutoff
+            + self.modulation_envelope.value() * self.modulation_envelope_to_filter_cutoff
+    }
+
+    fn gain(&self) -> f32 {
+        if self.modulation_lfo_to_volume == 0.0 {
+            return 1.0;
+        }
+        decibels_to_gain(self.modulation_lfo.value() * self.modulation_lfo_to_volume)
+    }
 }
 
-#[derive(Default, Clone)]
-pub struct SyncedMidiInfo {
-    pub beat: f64,
-    pub beats_per_second: f64,
+/// Passes quiet levels through untouched and smoothly squashes anything louder into the -1 to 1 range
+fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= SOFT_CLIP_THRESHOLD {
+        return sample;
+    }
+    let headroom = 1.0 - SOFT_CLIP_THRESHOLD;
+    let clipped =
+        SOFT_CLIP_THRESHOLD + headroom * ((magnitude - SOFT_CLIP_THRESHOLD) / headroom).tanh();
+    clipped.copysign(sample)
 }
 
-pub enum MidiBufferMessage {
-    Audio(i16),
+/// Constant-power left and right gains for a pan from -50 (left) to 50 (right)
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-50.0, 50.0) + 50.0) / 100.0 * FRAC_PI_2;
+    (angle.cos(), angle.sin())
 }
 
-pub struct SoundFontBank {
-    soundfont: Arc<SoundFont>,
-    preset_index: HashMap<(u8, u8), usize>,
+/// How many cents A4 at `frequency` Hz is from A4 at 440 Hz
+fn frequency_to_cents(frequency: f32) -> f32 {
+    1200.0 * (frequency / 440.0).log2()
 }
 
-impl SoundFontBank {
-    pub fn new(soundfont: Arc<SoundFont>) -> Self {
-        let preset_index = soundfont
-            .get_presets()
-            .iter()
-            .enumerate()
-            .map(|(index, preset)| {
-                (
-                    (
-                        preset.get_bank_number() as u8,
-                        preset.get_patch_number() as u8,
-                    ),
-                    index,
-                )
-            })
-            .collect();
+fn cents_to_ratio(cents: f32) -> f32 {
+    if cents == 0.0 {
+        1.0
+    } else {
+        2_f32.powf(cents / 1200.0)
+    }
+}
+
+struct Channel {
+    bank_number: u8,
+    patch_number: u8,
+    /// Set by CC0, applied on the next program change
+    bank_select: u8,
+    /// Set by CC32, applied on the next program change
+    bank_select_fine: u8,
+    /// Whether the patch was set in code, so bank selects and program changes get ignored
+    patch_locked: bool,
+    /// Whether the channel is a drum part, by default on channel 10 or through a GS drum part SysEx,
+    /// so program changes keep it on the percussion bank whatever the bank selects say
+    drum_part: bool,
+    /// Voices on lower priority channels get stolen first by [`VoiceStealingPolicy::LowestPriority`]
+    priority: u8,
+    /// Whether the sustain pedal (CC64) is down, holding voices after their note off
+    sustain: bool,
+    /// Whether the sostenuto pedal (CC66) is down, holding the voices that were held when it was pressed
+    sostenuto: bool,
+    /// Whether the soft pedal (CC67) is down, making new notes quieter and duller
+    soft: bool,
+    /// Whether portamento (CC65) is on, gliding new notes from the last note's pitch
+    portamento: bool,
+    /// In seconds, set by CC5
+    portamento_time: f32,
+    /// The last note started, for portamento to glide from
+    last_note: Option<u8>,
+    /// Whether the channel is in mono mode (CC126), where new notes take over the sounding voice
+    mono: bool,
+    /// Keys held down in mono mode and their velocities, oldest first
+    held_notes: Vec<(u8, u8)>,
+    /// Every controller's latest value, for modulators to read
+    controllers: [u8; 128],
+    /// Aftertouch for the whole channel
+    channel_pressure: u8,
+    /// 14 bits, centered on 8192
+    pitch_wheel: u16,
+    /// In cents, how far a full pitch bend goes, set by RPN 0
+    pitch_bend_range: f32,
+    /// 14 bits centered on 8192, from -100 to 100 cents, set by RPN 1
+    fine_tuning: u16,
+    /// Centered on 64, in semitones, set by RPN 2
+    coarse_tuning: u8,
+    /// The pitch of every key, set in code or by MIDI Tuning Standard messages
+    tuning_table: TuningTable,
+    /// The bank and MIDI Tuning Standard tuning program the channel uses, set by RPN 4 and RPN 3
+    tuning_program: (u8, u8),
+    /// Set by RPN 4, applied on the next tuning program select
+    tuning_bank_select: u8,
+    /// Selected by CC101 and CC100, `None` while a non-registered parameter is selected
+    registered_parameter: Option<u16>,
+    /// Held voices, oldest first
+    voices: Vec<Voice>,
+    /// Voices that got a note off or were stolen, but are still fading out
+    released_voices: Vec<Voice>,
+    /// How many voices count towards voice limits, kept up to date as voices start, get stolen and finish
+    active_voices: usize,
+}
+
+impl Channel {
+    fn new(bank_number: u8, patch_number: u8) -> Self {
         Self {
-            soundfont,
-            preset_index,
+            bank_number,
+            patch_number,
+            bank_select: 0,
+            bank_select_fine: 0,
+            patch_locked: false,
+            drum_part: bank_number == PERCUSSION_BANK,
+            priority: 0,
+            sustain: false,
+            sostenuto: false,
+            soft: false,
+            portamento: false,
+            portamento_time: DEFAULT_PORTAMENTO_TIME,
+            last_note: None,
+            mono: false,
+            held_notes: vec![],
+            controllers: default_controllers(),
+            channel_pressure: 0,
+            pitch_wheel: 8192,
+            pitch_bend_range: 200.0,
+            fine_tuning: 8192,
+            coarse_tuning: 64,
+            tuning_table: TuningTable::default(),
+            tuning_program: (0, 0),
+            tuning_bank_select: 0,
+            registered_parameter: None,
+            voices: vec![],
+            released_voices: vec![],
+            active_voices: 0,
         }
     }
 
-    pub fn get_sample_headers(
-        &self,
-        note: i32,
-        velocity: i32,
-        bank_number: u8,
-        patch_number: u8,
-    ) -> Option<Vec<&SampleHeader>> {
-        let &preset_index = self.preset_index.get(&(bank_number, patch_number))?;
-        let preset = &self.soundfont.get_presets()[preset_index];
-        let preset_regions = preset
-            .get_regions()
+    fn all_voices(&self) -> impl Iterator<Item = &Voice> {
+        self.voices.iter().chain(self.released_voices.iter())
+    }
+
+    fn all_voices_mut(&mut self) -> impl Iterator<Item = &mut Voice> {
+        self.voices
+            .iter_mut()
+            .chain(self.released_voices.iter_mut())
+    }
+
+    fn control_change(&mut self, controller: u8, value: u8) {
+        self.controllers[controller as usize & 0x7f] = value;
+        match Controller::try_from(controller) {
+            Ok(Controller::Sustain) => {
+                self.sustain = value >= 64;
+                self.release_pedalled_voices();
+            }
+            Ok(Controller::Sostenuto) => {
+                let sostenuto = value >= 64;
+                if sostenuto && !self.sostenuto {
+                    self.voices
+                        .iter_mut()
+                        .filter(|voice| !voice.note_off)
+                        .for_each(|voice| voice.sostenuto = true);
+                } else if !sostenuto {
+                    self.voices
+                        .iter_mut()
+                        .for_each(|voice| voice.sostenuto = false);
+                }
+                self.sostenuto = sostenuto;
+                self.release_pedalled_voices();
+            }
+            Ok(Controller::Soft) => self.soft = value >= 64,
+            Ok(Controller::PortamentoTime) => {
+                self.portamento_time = (value as f32 / 127.0).powi(2) * MAX_PORTAMENTO_TIME;
+            }
+            Ok(Controller::Portamento) => self.portamento = value >= 64,
+            Ok(Controller::MonoMode) => {
+                self.all_notes_off();
+                self.mono = true;
+            }
+            Ok(Controller::PolyMode) => {
+                self.all_notes_off();
+                self.mono = false;
+            }
+            Ok(Controller::AllSoundOff) => self.all_sound_off(),
+            Ok(Controller::ResetAllControllers) => self.reset_all_controllers(),
+            Ok(Controller::AllNotesOff) => self.all_notes_off(),
+            Ok(Controller::RegisteredParameter) => {
+                let fine = self.registered_parameter.unwrap_or(0) & 0x7f;
+                self.registered_parameter = Some(((value as u16) << 7) | fine);
+            }
+            Ok(Controller::RegisteredParameterFine) => {
+                let coarse = self.registered_parameter.unwrap_or(0) & !0x7f;
+                self.registered_parameter = Some(coarse | value as u16);
+            }
+            Ok(Controller::NonRegisteredParameter | Controller::NonRegisteredParameterFine) => {
+                self.registered_parameter = None;
+            }
+            Ok(Controller::DataEntry) => {
+                match self.registered_parameter.map(RegisteredParameter::try_from) {
+                    Some(Ok(RegisteredParameter::PitchBendRange)) => {
+                        self.pitch_bend_range =
+                            value as f32 * 100.0 + self.pitch_bend_range % 100.0;
+                    }
+                    Some(Ok(RegisteredParameter::FineTuning)) => {
+                        self.fine_tuning = ((value as u16) << 7) | (self.fine_tuning & 0x7f);
+                    }
+                    Some(Ok(RegisteredParameter::CoarseTuning)) => self.coarse_tuning = value,
+                    Some(Ok(RegisteredParameter::TuningProgram)) => {
+                        self.tuning_program = (self.tuning_bank_select, value);
+                    }
+                    Some(Ok(RegisteredParameter::TuningBank)) => self.tuning_bank_select = value,
+                    _ => {}
+                }
+            }
+            Ok(Controller::DataEntryFine) => {
+                match self.registered_parameter.map(RegisteredParameter::try_from) {
+                    Some(Ok(RegisteredParameter::PitchBendRange)) => {
+                        self.pitch_bend_range =
+                            (self.pitch_bend_range / 100.0).floor() * 100.0 + value.min(99) as f32;
+                    }
+                    Some(Ok(RegisteredParameter::FineTuning)) => {
+                        self.fine_tuning = (self.fine_tuning & !0x7f) | value as u16;
+                    }
+                    _ => {}
+                }
+            }
+            // Read by the modulators from the controller values
+            Ok(
+                Controller::Modulation
+                | Controller::Volume
+                | Controller::Pan
+                | Controller::Expression,
+            )
+            | Err(_) => {}
+        }
+    }
+
+    /// Puts the channel back to how it started, except for a patch set in code, and releases its notes
+    fn reset(&mut self, drums: bool) {
+        self.set_drums(drums);
+        if !self.patch_locked {
+            self.patch_number = 0;
+        }
+        self.bank_select = 0;
+        self.bank_select_fine = 0;
+        self.controllers = default_controllers();
+        self.channel_pressure = 0;
+        self.pitch_wheel = 8192;
+        self.pitch_bend_range = 200.0;
+        self.fine_tuning = 8192;
+        self.coarse_tuning = 64;
+        self.registered_parameter = None;
+        self.sustain = false;
+        self.sostenuto = false;
+        self.soft = false;
+        self.portamento = false;
+        self.last_note = None;
+        self.mono = false;
+        self.all_voices_mut().for_each(|voice| {
+            voice.sostenuto = false;
+            voice.poly_pressure = 0;
+        });
+        self.all_notes_off();
+    }
+
+    fn set_drums(&mut self, drums: bool) {
+        if self.patch_locked {
+            return;
+        }
+        self.drum_part = drums;
+        self.bank_number = if drums { PERCUSSION_BANK } else { 0 };
+    }
+
+    fn program_change(&mut self, program: u8) {
+        if self.patch_locked {
+            return;
+        }
+        self.patch_number = program;
+        // Drum parts can't be switched to a melodic bank, since GM files often select bank 0 on them anyway.
+        // Drums picked by a bank select go back to melodic with the next one, like XG files do.
+        if !self.drum_part {
+            // GS style files pick the bank with CC0, XG style ones pick variations with CC32
+            self.bank_number = match (self.bank_select, self.bank_select_fine) {
+                (XG_DRUM_BANK_SELECT | GM2_DRUM_BANK_SELECT, _) => PERCUSSION_BANK,
+                (0, bank_select_fine) => bank_select_fine,
+                (bank_select, _) => bank_select,
+            };
+        }
+    }
+
+    /// Moves the newest voice over to `note`, gliding there if portamento is on
+    fn legato(&mut self, note: u8) {
+        let Some(voice) = self.voices.last() else {
+            return;
+        };
+        let cents = self.key_pitch(note) - self.key_pitch(voice.note);
+        let glide_time = self.portamento.then_some(self.portamento_time);
+        if let Some(voice) = self.voices.last_mut() {
+            voice.legato(note, cents, glide_time);
+        }
+        self.last_note = Some(note);
+        self.update_modulation(|source| source == ModulatorSource::Key);
+    }
+
+    /// The pitch of a key in cents above key 0, including the tuning table
+    fn key_pitch(&self, note: u8) -> f32 {
+        note as f32 * 100.0 + self.tuning_table.offset(note)
+    }
+
+    /// In cents, from the fine and coarse tuning
+    fn tuning(&self) -> f32 {
+        (self.coarse_tuning as f32 - 64.0) * 100.0
+            + (self.fine_tuning as f32 - 8192.0) / 8192.0 * 100.0
+    }
+
+    /// What the modulators read for a voice on the channel
+    fn modulator_state(&self, velocity: u8, key: u8, poly_pressure: u8) -> ModulatorState<'_> {
+        ModulatorState {
+            velocity,
+            key,
+            poly_pressure,
+            channel_pressure: self.channel_pressure,
+            pitch_wheel: self.pitch_wheel,
+            pitch_wheel_sensitivity: self.pitch_bend_range / 100.0,
+            controllers: &self.controllers,
+        }
+    }
+
+    /// Evaluates the modulators reading a source that `changed` matches again, for every voice on the channel
+    fn update_modulation(&mut self, changed: impl Fn(ModulatorSource) -> bool) {
+        let controllers = self.controllers;
+        let state = ModulatorState {
+            controllers: &controllers,
+            ..self.modulator_state(0, 0, 0)
+        };
+        for voice in self.all_voices_mut() {
+            voice.update_modulation(
+                &ModulatorState {
+                    velocity: voice.velocity,
+                    key: voice.note,
+                    poly_pressure: voice.poly_pressure,
+                    ..state
+                },
+                &changed,
+            );
+        }
+    }
+
+    /// Quickly fades out every sample in any of the exclusive classes, like an open hi-hat being cut off by a closed one
+    fn choke(&mut self, exclusive_classes: &[i32]) {
+        if exclusive_classes.is_empty() {
+            return;
+        }
+        self.all_voices_mut()
+            .for_each(|voice| voice.choke(exclusive_classes));
+    }
+
+    /// Whether a key is down for the note, ignoring voices only held by the pedals
+    fn is_held(&self, note: u8) -> bool {
+        self.voices
             .iter()
-            .filter(|region| region.contains(note, velocity));
-        let instruments = preset_regions
-            .map(|region| &self.soundfont.get_instruments()[region.get_instrument_id()]);
-        let instrument_regions = instruments.flat_map(|instrument| {
-            instrument
-                .get_regions()
-                .iter()
-                .filter(|region| region.contains(note, velocity))
+            .any(|voice| voice.note == note && !voice.note_off)
+    }
+
+    /// Releases the oldest held voice playing the note, unless it's still waiting on note offs from voices it retriggered.
+    /// Voices held by the pedals only release once the pedals let go.
+    fn release_voice(&mut self, note: u8) {
+        let Some(index) = self
+            .voices
+            .iter()
+            .position(|voice| voice.note == note && !voice.note_off)
+        else {
+            return;
+        };
+        let voice = &mut self.voices[index];
+        if voice.pending_note_offs > 0 {
+            voice.pending_note_offs -= 1;
+            return;
+        }
+        voice.note_off = true;
+        self.release_pedalled_voices();
+    }
+
+    /// Quickly fades out every voice, ignoring release times and pedals
+    fn all_sound_off(&mut self) {
+        self.released_voices.append(&mut self.voices);
+        self.released_voices.iter_mut().for_each(Voice::steal);
+        self.active_voices = 0;
+    }
+
+    /// Quickly fades out the voices with the given ids, moving held ones over to the released voices
+    fn steal(&mut self, ids: &HashSet<u64>) {
+        let (stolen, held) = std::mem::take(&mut self.voices)
+            .into_iter()
+            .partition::<Vec<_>, _>(|voice| ids.contains(&voice.id));
+        self.voices = held;
+        self.released_voices.extend(stolen);
+        for voice in self
+            .released_voices
+            .iter_mut()
+            .filter(|voice| !voice.stolen && ids.contains(&voice.id))
+        {
+            voice.steal();
+            self.active_voices -= 1;
+        }
+    }
+
+    /// Releases every held voice straight away, even ones the pedals hold, for a new mono note to take over
+    fn release_held_voices(&mut self) {
+        for mut voice in self.voices.drain(..) {
+            voice.release();
+            self.released_voices.push(voice);
+        }
+    }
+
+    /// Gives every held voice a note off, so they still wait for the pedals
+    fn all_notes_off(&mut self) {
+        self.held_notes.clear();
+        self.voices.iter_mut().for_each(|voice| {
+            voice.note_off = true;
+            voice.pending_note_offs = 0;
         });
-        let sample_headers = instrument_regions
-            .map(|region| &self.soundfont.get_sample_headers()[region.get_sample_id()]);
-        Some(sample_headers.collect())
+        self.release_pedalled_voices();
+    }
+
+    /// Resets the controllers that performances change back to their defaults, leaving mix settings like volume and pan alone
+    fn reset_all_controllers(&mut self) {
+        let defaults = default_controllers();
+        for controller in [
+            Controller::Modulation,
+            Controller::Expression,
+            Controller::Sustain,
+            Controller::Portamento,
+            Controller::Sostenuto,
+            Controller::Soft,
+        ] {
+            self.controllers[controller as usize] = defaults[controller as usize];
+        }
+        self.channel_pressure = 0;
+        self.pitch_wheel = 8192;
+        self.registered_parameter = None;
+        self.all_voices_mut()
+            .for_each(|voice| voice.poly_pressure = 0);
+
+        self.sustain = false;
+        self.portamento = false;
+        self.sostenuto = false;
+        self.soft = false;
+        self.voices
+            .iter_mut()
+            .for_each(|voice| voice.sostenuto = false);
+        self.release_pedalled_voices();
+    }
+
+    /// Releases the voices that got their note off and aren't held by a pedal anymore
+    fn release_pedalled_voices(&mut self) {
+        let sustain = self.sustain;
+        let (released, held) = std::mem::take(&mut self.voices)
+            .into_iter()
+            .partition::<Vec<_>, _>(|voice| voice.note_off && !sustain && !voice.sostenuto);
+        self.voices = held;
+        for mut voice in released {
+            voice.release();
+            self.released_voices.push(voice);
+        }
+    }
+
+    /// Releases every held voice playing the note, returning how many note offs they were still waiting on
+    fn retrigger(&mut self, note: u8) -> u32 {
+        let mut pending_note_offs = 0;
+        let (retriggered, held) = std::mem::take(&mut self.voices)
+            .into_iter()
+            .partition::<Vec<_>, _>(|voice| voice.note == note);
+        self.voices = held;
+        for mut voice in retriggered {
+            if !voice.note_off {
+                pending_note_offs += voice.pending_note_offs + 1;
+            }
+            voice.release();
+            self.released_voices.push(voice);
+        }
+        pending_note_offs
     }
+
+    fn render(&mut self, wave_data: &[i16], interpolation: InterpolationMode) {
+        self.all_voices_mut()
+            .for_each(|voice| voice.render(wave_data, interpolation));
+    }
+
+    fn tick(&mut self, delta: f32) {
+        self.all_voices_mut().for_each(|voice| voice.tick(delta));
+        // Held voices can finish too, like one-shot samples
+        let active_voices = &mut self.active_voices;
+        let mut retain = |voice: &Voice| {
+            let finished = voice.is_finished();
+            if finished && !voice.stolen {
+                *active_voices -= 1;
+            }
+            !finished
+        };
+        self.voices.retain(&mut retain);
+        self.released_voices.retain(&mut retain);
+    }
+}
+
+/// The general MIDI power-on controller values
+fn default_controllers() -> [u8; 128] {
+    let mut controllers = [0; 128];
+    controllers[Controller::Volume as usize] = 100;
+    controllers[Controller::Pan as usize] = 64;
+    controllers[Controller::Expression as usize] = 127;
+    controllers
+}
+
+#[derive(Default, Clone)]
+pub struct SyncedMidiInfo {
+    pub beat: f64,
+    pub beats_per_second: f64,
+}
+
+pub enum MidiBufferMessage {
+    Audio(f32),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -597,3 +2114,392 @@ pub enum MidiQueueLooping {
     Loop,
     Once,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulator::{ModulatorDestination, ModulatorInput, ModulatorOutputs};
+
+    /// A track without events, for sending events to by hand
+    fn empty_track() -> MidiAudioTrack {
+        MidiAudioTrack::new(
+            MidiTrack {
+                events: vec![],
+                ticks_per_beat: 480,
+            },
+            1.0,
+        )
+    }
+
+    /// Plays `track` on the bundled SoundFont
+    fn audio_with(track: MidiAudioTrack) -> (MidiAudio, MidiAudioTrackHandle) {
+        let mut audio = MidiAudio::from_bytes(include_bytes!("../assets/hl4mgm.sf2"));
+        let track = audio.add_track(track);
+        (audio, track)
+    }
+
+    fn audio() -> (MidiAudio, MidiAudioTrackHandle) {
+        audio_with(empty_track())
+    }
+
+    fn channel(audio: &MidiAudio, track: MidiAudioTrackHandle) -> &Channel {
+        &audio.tracks[&track].channels[&0]
+    }
+
+    fn note_on(audio: &mut MidiAudio, track: MidiAudioTrackHandle, note: u8) {
+        audio.interpret_event(
+            track,
+            MidiEvent::NoteOn {
+                channel: 0,
+                note,
+                velocity: 127,
+            },
+        );
+    }
+
+    fn note_off(audio: &mut MidiAudio, track: MidiAudioTrackHandle, note: u8) {
+        audio.interpret_event(track, MidiEvent::NoteOff { channel: 0, note });
+    }
+
+    /// The notes of the voices still held on channel 0, oldest first
+    fn held_notes(audio: &MidiAudio, track: MidiAudioTrackHandle) -> Vec<u8> {
+        channel(audio, track)
+            .voices
+            .iter()
+            .map(|voice| voice.note)
+            .collect()
+    }
+
+    fn control_change(
+        audio: &mut MidiAudio,
+        track: MidiAudioTrackHandle,
+        controller: Controller,
+        value: u8,
+    ) {
+        audio.control_change(track, 0, controller, value);
+    }
+
+    /// The summed modulator outputs of the first sample of each held voice
+    fn modulation(audio: &MidiAudio, track: MidiAudioTrackHandle) -> Vec<ModulatorOutputs> {
+        channel(audio, track)
+            .voices
+            .iter()
+            .map(|voice| *voice.samples[0].modulation.outputs())
+            .collect()
+    }
+
+    fn select_registered_parameter(
+        audio: &mut MidiAudio,
+        track: MidiAudioTrackHandle,
+        coarse: u8,
+        fine: u8,
+    ) {
+        control_change(audio, track, Controller::RegisteredParameter, coarse);
+        control_change(audio, track, Controller::RegisteredParameterFine, fine);
+    }
+
+    #[test]
+    fn pitch_bend_moves_held_voices() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        audio.interpret_event(
+            track,
+            MidiEvent::PitchBend {
+                channel: 0,
+                value: 0,
+            },
+        );
+        // The default bend range is 2 semitones
+        assert!((modulation(&audio, track)[0].pitch + 200.0).abs() < 0.01);
+        audio.interpret_event(
+            track,
+            MidiEvent::PitchBend {
+                channel: 0,
+                value: 8192,
+            },
+        );
+        assert!(modulation(&audio, track)[0].pitch.abs() < 0.01);
+    }
+
+    #[test]
+    fn rpn_0_sets_the_bend_range() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        audio.interpret_event(
+            track,
+            MidiEvent::PitchBend {
+                channel: 0,
+                value: 0,
+            },
+        );
+        select_registered_parameter(&mut audio, track, 0, 0);
+        control_change(&mut audio, track, Controller::DataEntry, 12);
+        control_change(&mut audio, track, Controller::DataEntryFine, 50);
+        assert_eq!(channel(&audio, track).pitch_bend_range, 1250.0);
+        // Held voices follow the new range straight away
+        assert!((modulation(&audio, track)[0].pitch + 1250.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn data_entry_ignores_the_null_rpn_and_nrpns() {
+        let (mut audio, track) = audio();
+        select_registered_parameter(&mut audio, track, 0, 0);
+        select_registered_parameter(&mut audio, track, 127, 127);
+        control_change(&mut audio, track, Controller::DataEntry, 24);
+        assert_eq!(channel(&audio, track).pitch_bend_range, 200.0);
+
+        select_registered_parameter(&mut audio, track, 0, 0);
+        control_change(&mut audio, track, Controller::NonRegisteredParameter, 0);
+        control_change(&mut audio, track, Controller::NonRegisteredParameterFine, 0);
+        control_change(&mut audio, track, Controller::DataEntry, 24);
+        assert_eq!(channel(&audio, track).pitch_bend_range, 200.0);
+    }
+
+    /// The attenuation in dB of the one held voice, from its modulators
+    fn attenuation(audio: &MidiAudio, track: MidiAudioTrackHandle) -> f32 {
+        modulation(audio, track)[0].attenuation
+    }
+
+    #[test]
+    fn volume_and_expression_attenuate_held_voices() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        control_change(&mut audio, track, Controller::Volume, 127);
+        assert!(attenuation(&audio, track).abs() < 0.01);
+
+        // The concave curve makes half way down about 12 dB quieter, like GM asks for
+        control_change(&mut audio, track, Controller::Volume, 64);
+        let half_volume = attenuation(&audio, track);
+        assert!((10.0..14.0).contains(&half_volume));
+        // Expression stacks on top of volume with the same curve
+        control_change(&mut audio, track, Controller::Expression, 64);
+        assert!((attenuation(&audio, track) - half_volume * 2.0).abs() < 0.01);
+
+        control_change(&mut audio, track, Controller::Expression, 0);
+        assert!(attenuation(&audio, track) >= 96.0);
+    }
+
+    #[test]
+    fn channel_volume_setters_act_like_their_controllers() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        audio.set_channel_volume(track, 0, 64);
+        audio.set_channel_expression(track, 0, 100);
+        let controllers = channel(&audio, track).controllers;
+        assert_eq!(controllers[Controller::Volume as usize], 64);
+        assert_eq!(controllers[Controller::Expression as usize], 100);
+        assert!(attenuation(&audio, track) > 0.0);
+    }
+
+    #[test]
+    fn master_volume_scales_the_mix() {
+        let render = |master_volume| {
+            let (mut audio, track) = audio();
+            audio.set_master_volume(master_volume);
+            note_on(&mut audio, track, 60);
+            audio.tick(Duration::from_millis(50));
+            audio
+                .buffer
+                .lock()
+                .unwrap()
+                .iter()
+                .fold(0.0_f32, |peak, sample| peak.max(sample.abs()))
+        };
+        assert!(render(1.0) > 0.0);
+        assert_eq!(render(0.0), 0.0);
+    }
+
+    #[test]
+    fn sustain_holds_notes_until_the_pedal_lifts() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        control_change(&mut audio, track, Controller::Sustain, 127);
+        note_off(&mut audio, track, 60);
+        assert_eq!(held_notes(&audio, track), [60]);
+        // Notes started while the pedal is down are held too
+        note_on(&mut audio, track, 64);
+        note_off(&mut audio, track, 64);
+        assert_eq!(held_notes(&audio, track), [60, 64]);
+
+        control_change(&mut audio, track, Controller::Sustain, 0);
+        assert!(held_notes(&audio, track).is_empty());
+        assert_eq!(channel(&audio, track).released_voices.len(), 2);
+    }
+
+    #[test]
+    fn sostenuto_only_holds_notes_held_when_pressed() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        control_change(&mut audio, track, Controller::Sostenuto, 127);
+        note_on(&mut audio, track, 64);
+        note_off(&mut audio, track, 60);
+        note_off(&mut audio, track, 64);
+        assert_eq!(held_notes(&audio, track), [60]);
+
+        // Pressing it again while down doesn't latch anything new
+        note_on(&mut audio, track, 67);
+        control_change(&mut audio, track, Controller::Sostenuto, 127);
+        note_off(&mut audio, track, 67);
+        assert_eq!(held_notes(&audio, track), [60]);
+
+        control_change(&mut audio, track, Controller::Sostenuto, 0);
+        assert!(held_notes(&audio, track).is_empty());
+    }
+
+    #[test]
+    fn sostenuto_notes_wait_for_the_sustain_pedal_too() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        control_change(&mut audio, track, Controller::Sostenuto, 127);
+        control_change(&mut audio, track, Controller::Sustain, 127);
+        note_off(&mut audio, track, 60);
+        control_change(&mut audio, track, Controller::Sostenuto, 0);
+        assert_eq!(held_notes(&audio, track), [60]);
+        control_change(&mut audio, track, Controller::Sustain, 0);
+        assert!(held_notes(&audio, track).is_empty());
+    }
+
+    #[test]
+    fn soft_pedal_makes_new_notes_quieter_and_duller() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        control_change(&mut audio, track, Controller::Soft, 127);
+        note_on(&mut audio, track, 64);
+        let voices = &channel(&audio, track).voices;
+        let (normal, soft) = (&voices[0].samples[0], &voices[1].samples[0]);
+        assert!(soft.volume < normal.volume);
+        assert!(soft.filter_cutoff < normal.filter_cutoff);
+    }
+
+    #[test]
+    fn channel_pressure_deepens_vibrato() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        audio.set_channel_pressure(track, 0, 127);
+        // The default SF2 modulator adds up to 50 cents of vibrato
+        note_on(&mut audio, track, 64);
+        for outputs in modulation(&audio, track) {
+            assert!((outputs.vibrato_lfo_to_pitch - 50.0).abs() < 0.01);
+        }
+        audio.set_channel_pressure(track, 0, 0);
+        for outputs in modulation(&audio, track) {
+            assert!(outputs.vibrato_lfo_to_pitch.abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn poly_pressure_only_modulates_its_note() {
+        let (mut audio, track) = audio_with(empty_track().with_modulator(Modulator::new(
+            ModulatorInput::new(ModulatorSource::PolyPressure),
+            ModulatorDestination::FilterCutoff,
+            -1200.0,
+        )));
+        note_on(&mut audio, track, 60);
+        note_on(&mut audio, track, 64);
+        audio.interpret_event(
+            track,
+            MidiEvent::PolyPressure {
+                channel: 0,
+                note: 64,
+                pressure: 127,
+            },
+        );
+        let outputs = modulation(&audio, track);
+        assert!(outputs[0].filter_cutoff.abs() < 0.01);
+        assert!((outputs[1].filter_cutoff + 1200.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn all_notes_off_waits_for_the_pedals() {
+        let (mut audio, track) = audio();
+        note_on(&mut audio, track, 60);
+        control_change(&mut audio, track, Controller::Sustain, 127);
+        note_on(&mut audio, track, 64);
+        audio.all_notes_off(track, None);
+        assert_eq!(held_notes(&audio, track), [60, 64]);
+        control_change(&mut audio, track, Controller::Sustain, 0);
+        assert!(held_notes(&audio, track).is_empty());
+        // Released normally rather than cut off
+        assert!(
+            channel(&audio, track)
+                .released_voices
+                .iter()
+                .all(|voice| !voice.stolen)
+        );
+    }
+
+    #[test]
+    fn all_sound_off_cuts_off_every_voice() {
+        let (mut audio, track) = audio();
+        control_change(&mut audio, track, Controller::Sustain, 127);
+        note_on(&mut audio, track, 60);
+        note_on(&mut audio, track, 64);
+        note_off(&mut audio, track, 64);
+        // Other channels are left alone
+        audio.all_sound_off(track, Some(1));
+        assert_eq!(held_notes(&audio, track), [60, 64]);
+
+        audio.all_sound_off(track, Some(0));
+        let channel = channel(&audio, track);
+        assert!(channel.voices.is_empty());
+        assert!(channel.released_voices.iter().all(|voice| voice.stolen));
+        assert_eq!(channel.active_voices, 0);
+    }
+
+    #[test]
+    fn reset_all_controllers_leaves_mix_settings_alone() {
+        let (mut audio, track) = audio();
+        control_change(&mut audio, track, Controller::Volume, 50);
+        control_change(&mut audio, track, Controller::Expression, 40);
+        control_change(&mut audio, track, Controller::Sustain, 127);
+        audio.interpret_event(
+            track,
+            MidiEvent::PitchBend {
+                channel: 0,
+                value: 0,
+            },
+        );
+        note_on(&mut audio, track, 60);
+        note_off(&mut audio, track, 60);
+
+        audio.reset_all_controllers(track, Some(0));
+        let channel = channel(&audio, track);
+        assert_eq!(channel.pitch_wheel, 8192);
+        assert!(!channel.sustain);
+        // Lifting the sustain pedal releases the notes it held
+        assert!(channel.voices.is_empty());
+        assert_eq!(channel.controllers[Controller::Expression as usize], 127);
+        assert_eq!(channel.controllers[Controller::Volume as usize], 50);
+        let outputs = channel.released_voices[0].samples[0].modulation.outputs();
+        assert!(outputs.pitch.abs() < 0.01);
+    }
+
+    #[test]
+    fn drum_bank_select_switches_back_to_melodic() {
+        let mut channel = Channel::new(0, 0);
+        channel.bank_select = XG_DRUM_BANK_SELECT;
+        channel.program_change(0);
+        assert_eq!(channel.bank_number, PERCUSSION_BANK);
+        channel.bank_select = 0;
+        channel.bank_select_fine = 0;
+        channel.program_change(0);
+        assert_eq!(channel.bank_number, 0);
+    }
+
+    #[test]
+    fn drum_parts_ignore_bank_selects() {
+        let mut channel = Channel::new(PERCUSSION_BANK, 0);
+        channel.bank_select = 1;
+        channel.program_change(0);
+        assert_eq!(channel.bank_number, PERCUSSION_BANK);
+
+        let mut channel = Channel::new(0, 0);
+        channel.set_drums(true);
+        channel.program_change(0);
+        assert_eq!(channel.bank_number, PERCUSSION_BANK);
+        channel.set_drums(false);
+        channel.bank_select = 8;
+        channel.program_change(0);
+        assert_eq!(channel.bank_number, 8);
+    }
+}
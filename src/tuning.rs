@@ -0,0 +1,384 @@
+/// Maps every MIDI key to a pitch, stored as how far each key is from its 12-tone equal temperament pitch at A4 = 440 Hz
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningTable {
+    /// In cents
+    offsets: [f32; 128],
+}
+
+impl Default for TuningTable {
+    fn default() -> Self {
+        Self::equal_temperament()
+    }
+}
+
+impl TuningTable {
+    /// 12-tone equal temperament, the tuning voices use without a table
+    pub fn equal_temperament() -> Self {
+        Self {
+            offsets: [0.0; 128],
+        }
+    }
+
+    /// From how far each key is from its equal temperament pitch, in cents
+    pub fn from_offsets(offsets: [f32; 128]) -> Self {
+        Self { offsets }
+    }
+
+    /// From the frequency of each key, in Hz
+    pub fn from_frequencies(frequencies: [f32; 128]) -> Self {
+        let mut table = Self::equal_temperament();
+        for (key, frequency) in frequencies.into_iter().enumerate() {
+            table.set_frequency(key as u8, frequency);
+        }
+        table
+    }
+
+    /// From a Scala scale (`.scl`) and optionally a Scala keyboard mapping (`.kbm`).
+    /// Without a keyboard mapping the scale starts on middle C, with A4 at 440 Hz.
+    /// Keys the mapping leaves out keep their equal temperament pitch.
+    pub fn from_scala(scale: &str, keyboard_mapping: Option<&str>) -> Result<Self, ScalaError> {
+        let scale = parse_scale(scale)?;
+        let mapping = match keyboard_mapping {
+            Some(keyboard_mapping) => parse_keyboard_mapping(keyboard_mapping)?,
+            None => KeyboardMapping::linear(scale.len() as i32),
+        };
+
+        let scale_size = scale.len() as i32;
+        let period = scale[scale.len() - 1];
+        let degree_cents = |degree: i32| {
+            let step = degree.rem_euclid(scale_size);
+            let octave = degree.div_euclid(scale_size) as f32 * period;
+            if step == 0 {
+                octave
+            } else {
+                octave + scale[step as usize - 1]
+            }
+        };
+
+        let reference_cents = mapping
+            .degree(mapping.reference_key)
+            .map(degree_cents)
+            .ok_or(ScalaError::UnmappedReferenceKey)?;
+        let mut table = Self::equal_temperament();
+        for key in mapping.first_key.max(0)..=mapping.last_key.min(127) {
+            if let Some(degree) = mapping.degree(key) {
+                let cents = degree_cents(degree) - reference_cents;
+                table.set_frequency(
+                    key as u8,
+                    mapping.reference_frequency * 2_f32.powf(cents / 1200.0),
+                );
+            }
+        }
+        Ok(table)
+    }
+
+    /// In cents, how far the key is from its equal temperament pitch
+    pub fn offset(&self, key: u8) -> f32 {
+        self.offsets[key as usize & 0x7f]
+    }
+
+    /// Sets how far the key is from its equal temperament pitch, in cents
+    pub fn set_offset(&mut self, key: u8, offset: f32) {
+        self.offsets[key as usize & 0x7f] = offset;
+    }
+
+    /// Sets the frequency of the key, in Hz
+    pub fn set_frequency(&mut self, key: u8, frequency: f32) {
+        let equal_temperament = (key as f32 - 69.0) * 100.0;
+        self.set_offset(key, 1200.0 * (frequency / 440.0).log2() - equal_temperament);
+    }
+
+    pub(crate) fn retune(&mut self, retuning: &Retuning) {
+        match retuning {
+            Retuning::Keys(keys) => {
+                for &(key, pitch) in keys {
+                    self.set_offset(key, pitch - key as f32 * 100.0);
+                }
+            }
+            Retuning::Octave(offsets) => {
+                for key in 0..128 {
+                    self.set_offset(key, offsets[key as usize % 12]);
+                }
+            }
+        }
+    }
+}
+
+/// A MIDI Tuning Standard retuning
+#[derive(Debug, Clone, PartialEq)]
+pub enum Retuning {
+    /// Keys and their new pitches, in cents above key 0 at equal temperament
+    Keys(Vec<(u8, f32)>),
+    /// How far each note of the octave is from equal temperament in cents, starting from C.
+    /// A scale/octave tuning gives every key its pitch class's offset, so it replaces the whole table rather than adding to it.
+    Octave([f32; 12]),
+}
+
+/// Why a Scala file couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScalaError {
+    /// The file ended before all of its values were read
+    MissingValue,
+    /// A value couldn't be read as a number, ratio or cents
+    InvalidValue(String),
+    /// The scale has no notes
+    EmptyScale,
+    /// The keyboard mapping leaves out the key it gives the frequency for
+    UnmappedReferenceKey,
+}
+
+/// How a Scala keyboard mapping (`.kbm`) lays a scale out across the keys
+struct KeyboardMapping {
+    first_key: i32,
+    last_key: i32,
+    /// The key scale degree 0 is mapped to
+    middle_key: i32,
+    reference_key: i32,
+    /// In Hz
+    reference_frequency: f32,
+    /// How many scale degrees one repetition of the mapping spans
+    octave_degree: i32,
+    /// The scale degree of each key in a repetition of the mapping, empty to map every key to the next degree
+    map: Vec<Option<i32>>,
+}
+
+impl KeyboardMapping {
+    /// The mapping used without a `.kbm` file
+    fn linear(octave_degree: i32) -> Self {
+        Self {
+            first_key: 0,
+            last_key: 127,
+            middle_key: 60,
+            reference_key: 69,
+            reference_frequency: 440.0,
+            octave_degree,
+            map: vec![],
+        }
+    }
+
+    fn degree(&self, key: i32) -> Option<i32> {
+        let key = key - self.middle_key;
+        if self.map.is_empty() {
+            return Some(key);
+        }
+        let size = self.map.len() as i32;
+        let degree = self.map[key.rem_euclid(size) as usize]?;
+        Some(key.div_euclid(size) * self.octave_degree + degree)
+    }
+}
+
+/// The first value of every line that isn't a comment
+fn scala_values(file: &str) -> impl Iterator<Item = &str> {
+    file.lines()
+        .filter(|line| !line.starts_with('!'))
+        .map(|line| line.split_whitespace().next().unwrap_or(""))
+}
+
+fn parse_value<T: std::str::FromStr>(value: Option<&str>) -> Result<T, ScalaError> {
+    let value = value.ok_or(ScalaError::MissingValue)?;
+    value
+        .parse()
+        .map_err(|_| ScalaError::InvalidValue(value.to_string()))
+}
+
+/// The pitch of every note of a `.scl` scale in cents, leaving out the implied 1/1 but including the period at the end
+fn parse_scale(scale: &str) -> Result<Vec<f32>, ScalaError> {
+    // The description is the first line, and can be empty
+    let mut lines = scale.lines().filter(|line| !line.starts_with('!')).skip(1);
+    let note_count: usize =
+        parse_value(lines.next().and_then(|line| line.split_whitespace().next()))?;
+    if note_count == 0 {
+        return Err(ScalaError::EmptyScale);
+    }
+    lines
+        .take(note_count)
+        .map(|line| {
+            parse_pitch(
+                line.split_whitespace()
+                    .next()
+                    .ok_or(ScalaError::MissingValue)?,
+            )
+        })
+        .chain(std::iter::repeat_with(|| Err(ScalaError::MissingValue)))
+        .take(note_count)
+        .collect()
+}
+
+/// Pitches with a period are in cents, otherwise they're ratios
+fn parse_pitch(pitch: &str) -> Result<f32, ScalaError> {
+    let invalid = || ScalaError::InvalidValue(pitch.to_string());
+    if pitch.contains('.') {
+        return pitch.parse().map_err(|_| invalid());
+    }
+    let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+    let numerator: f32 = numerator.parse().map_err(|_| invalid())?;
+    let denominator: f32 = denominator.parse().map_err(|_| invalid())?;
+    if numerator <= 0.0 || denominator <= 0.0 {
+        return Err(invalid());
+    }
+    Ok(1200.0 * (numerator / denominator).log2())
+}
+
+fn parse_keyboard_mapping(keyboard_mapping: &str) -> Result<KeyboardMapping, ScalaError> {
+    let mut values = scala_values(keyboard_mapping);
+    let size: usize = parse_value(values.next())?;
+    let mut mapping = KeyboardMapping {
+        first_key: parse_value(values.next())?,
+        last_key: parse_value(values.next())?,
+        middle_key: parse_value(values.next())?,
+        reference_key: parse_value(values.next())?,
+        reference_frequency: parse_value(values.next())?,
+        octave_degree: parse_value(values.next())?,
+        map: vec![],
+    };
+    // Missing entries are left unmapped like `x` ones
+    mapping.map = (0..size)
+        .map(|_| match values.next() {
+            Some("x") | Some("") | None => Ok(None),
+            value => parse_value(value).map(Some),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(mapping)
+}
+
+/// Reads a MIDI Tuning Standard pitch: a key and a 14 bit fraction of a semitone above it.
+/// Returns `None` for the reserved "no change" value.
+pub fn parse_mts_pitch(key: u8, fraction_high: u8, fraction_low: u8) -> Option<f32> {
+    if (key, fraction_high, fraction_low) == (0x7f, 0x7f, 0x7f) {
+        return None;
+    }
+    let fraction = ((fraction_high as u16) << 7) | fraction_low as u16;
+    Some(key as f32 * 100.0 + fraction as f32 * 100.0 / 16384.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn pitches_with_a_period_are_cents_and_others_are_ratios() {
+        assert_close(parse_pitch("100.0").unwrap(), 100.0);
+        assert_close(parse_pitch("701.955").unwrap(), 701.955);
+        assert_close(parse_pitch("3/2").unwrap(), 701.955);
+        assert_close(parse_pitch("2").unwrap(), 1200.0);
+        assert_eq!(
+            parse_pitch("0/1"),
+            Err(ScalaError::InvalidValue("0/1".to_string()))
+        );
+        assert_eq!(
+            parse_pitch("abc"),
+            Err(ScalaError::InvalidValue("abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn scale_skips_comments_and_keeps_the_period() {
+        let scale = "! meantone.scl\n!\nQuarter-comma meantone\n 2\n!\n5/4 major third\n2\n";
+        let scale = parse_scale(scale).unwrap();
+        assert_eq!(scale.len(), 2);
+        assert_close(scale[0], 386.314);
+        assert_close(scale[1], 1200.0);
+    }
+
+    #[test]
+    fn scale_with_too_few_notes_is_missing_a_value() {
+        assert_eq!(
+            parse_scale("Too short\n3\n100.0\n200.0\n"),
+            Err(ScalaError::MissingValue)
+        );
+        assert_eq!(parse_scale("No count\n"), Err(ScalaError::MissingValue));
+        assert_eq!(parse_scale("Empty\n0\n"), Err(ScalaError::EmptyScale));
+    }
+
+    #[test]
+    fn scale_without_a_keyboard_mapping_keeps_a4_at_440_hz() {
+        // 5 equal steps per octave, starting on middle C, which puts A4 9 steps up at 2160 cents
+        let scale = "5-EDO\n5\n240.0\n480.0\n720.0\n960.0\n2/1\n";
+        let table = TuningTable::from_scala(scale, None).unwrap();
+        assert_close(table.offset(69), 0.0);
+        assert_close(table.offset(70), 140.0);
+        assert_close(table.offset(74), 700.0);
+        assert_close(table.offset(68), -140.0);
+        assert_close(table.offset(60), -1260.0);
+    }
+
+    #[test]
+    fn keyboard_mapping_with_unmapped_keys_and_a_6_key_repetition() {
+        let scale = "5-EDO\n5\n240.0\n480.0\n720.0\n960.0\n2/1\n";
+        let keyboard_mapping = "! 6 keys per repetition, the third left unmapped
+6
+0
+127
+60
+60
+261.6256
+5
+! Mapping
+0
+1
+x
+2
+3
+4
+";
+        let table = TuningTable::from_scala(scale, Some(keyboard_mapping)).unwrap();
+        assert_close(table.offset(60), 0.0);
+        assert_close(table.offset(61), 140.0);
+        // Unmapped keys keep their equal temperament pitch
+        assert_close(table.offset(62), 0.0);
+        assert_close(table.offset(63), 180.0);
+        // The next repetition starts a period higher
+        assert_close(table.offset(66), 600.0);
+        assert_close(table.offset(59), -140.0);
+    }
+
+    #[test]
+    fn keyboard_mapping_degrees_wrap_around_the_repetition() {
+        let mapping = KeyboardMapping {
+            map: vec![Some(0), None, Some(1)],
+            ..KeyboardMapping::linear(2)
+        };
+        assert_eq!(mapping.degree(60), Some(0));
+        assert_eq!(mapping.degree(61), None);
+        assert_eq!(mapping.degree(62), Some(1));
+        assert_eq!(mapping.degree(63), Some(2));
+        assert_eq!(mapping.degree(59), Some(-1));
+        assert_eq!(mapping.degree(58), None);
+    }
+
+    #[test]
+    fn reference_key_must_be_mapped() {
+        let scale = "12-EDO\n1\n100.0\n";
+        let keyboard_mapping = "2\n0\n127\n60\n69\n440.0\n1\n0\nx\n";
+        assert_eq!(
+            TuningTable::from_scala(scale, Some(keyboard_mapping)),
+            Err(ScalaError::UnmappedReferenceKey)
+        );
+    }
+
+    #[test]
+    fn mts_pitch_is_a_key_and_a_fraction_of_a_semitone() {
+        assert_close(parse_mts_pitch(60, 0, 0).unwrap(), 6000.0);
+        assert_close(parse_mts_pitch(60, 0x40, 0).unwrap(), 6050.0);
+        assert_close(parse_mts_pitch(60, 0x7f, 0x7f).unwrap(), 6099.99);
+        assert_eq!(parse_mts_pitch(0x7f, 0x7f, 0x7f), None);
+    }
+
+    #[test]
+    fn octave_retuning_replaces_every_key() {
+        let mut table = TuningTable::from_offsets([5.0; 128]);
+        let mut offsets = [0.0; 12];
+        offsets[9] = -20.0;
+        table.retune(&Retuning::Octave(offsets));
+        assert_close(table.offset(69), -20.0);
+        assert_close(table.offset(57), -20.0);
+        assert_close(table.offset(60), 0.0);
+    }
+}